@@ -0,0 +1,102 @@
+// Runs an intcode program file, reading input from stdin and writing
+// outputs to stdout:
+//
+//     cargo run --bin intcode -- [--ascii | --debug] program.txt
+//
+// With --ascii, input lines are sent as character codes and outputs are
+// printed as text. With --debug, stdin is read as debugger commands
+// instead (`help` lists them). The exit status is 0 if the program halted
+// (or the debugger quit), 2 if it needed input after stdin ran out, 1 if
+// it failed and 3 for bad arguments or an unreadable file.
+
+use advent_of_code_2019::intcode;
+use intcode::debugger::Debugger;
+use intcode::io::{self, AsciiInput, AsciiOutput, Machine};
+use intcode::{Program, StopReason};
+
+use std::env;
+use std::fs;
+use std::process;
+
+const HALTED: i32 = 0;
+const FAILED: i32 = 1;
+const STARVED: i32 = 2;
+const USAGE: i32 = 3;
+
+fn usage() -> ! {
+    eprintln!("usage: intcode [--ascii | --debug] <program>");
+    process::exit(USAGE);
+}
+
+fn run(prog: Program, ascii: bool) -> Result<StopReason, intcode::IntcodeError> {
+    if ascii {
+        let input = AsciiInput::new(std::io::BufReader::new(std::io::stdin()));
+        Machine::new(prog, input, AsciiOutput::new(std::io::stdout())).run()
+    } else {
+        Machine::new(prog, io::stdin(), io::stdout()).run()
+    }
+}
+
+fn debug(prog: Program) -> i32 {
+    let stdin = std::io::stdin();
+    match Debugger::new(prog).repl(stdin.lock(), std::io::stdout()) {
+        Ok(()) => HALTED,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            FAILED
+        }
+    }
+}
+
+fn main() {
+    let mut ascii = false;
+    let mut debugging = false;
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--ascii" => ascii = true,
+            "--debug" => debugging = true,
+            _ if arg.starts_with('-') => usage(),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+    if ascii && debugging {
+        usage();
+    }
+
+    let code = match fs::read_to_string(&path) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(USAGE);
+        }
+    };
+    let prog = match Program::new(&code, "") {
+        Ok(prog) => prog,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            process::exit(FAILED);
+        }
+    };
+
+    if debugging {
+        process::exit(debug(prog));
+    }
+    process::exit(match run(prog, ascii) {
+        Ok(StopReason::Halted) => HALTED,
+        Ok(StopReason::NeedsInput) => {
+            eprintln!("program needs more input");
+            STARVED
+        }
+        Ok(reason) => {
+            eprintln!("stopped: {:?}", reason);
+            FAILED
+        }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            FAILED
+        }
+    });
+}
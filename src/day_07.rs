@@ -1,72 +1,36 @@
-#[path = "intcode.rs"]
-mod intcode;
+use advent_of_code_2019::intcode;
 
+use intcode::scheduler::{Outcome, Scheduler};
 use std::collections::HashSet;
 
-fn run_amps(code: &str, settings: &[usize], input0: i32) -> i32 {
-    let amp0 = intcode::Program::new(code, &format!("{}, {}", settings[0], input0));
-    let output0 = amp0.run_prog().get_output();
+fn run_amps(code: &str, settings: &[usize], input0: i128) -> i128 {
+    let amp0 = intcode::Program::new(code, &format!("{}, {}", settings[0], input0)).unwrap();
+    let output0 = amp0.run_snapshot().unwrap().get_output().unwrap();
 
-    let amp1 = intcode::Program::new(code, &format!("{}, {}", settings[1], output0));
-    let output1 = amp1.run_prog().get_output();
+    let amp1 = intcode::Program::new(code, &format!("{}, {}", settings[1], output0)).unwrap();
+    let output1 = amp1.run_snapshot().unwrap().get_output().unwrap();
 
-    let amp2 = intcode::Program::new(code, &format!("{}, {}", settings[2], output1));
-    let output2 = amp2.run_prog().get_output();
+    let amp2 = intcode::Program::new(code, &format!("{}, {}", settings[2], output1)).unwrap();
+    let output2 = amp2.run_snapshot().unwrap().get_output().unwrap();
 
-    let amp3 = intcode::Program::new(code, &format!("{}, {}", settings[3], output2));
-    let output3 = amp3.run_prog().get_output();
+    let amp3 = intcode::Program::new(code, &format!("{}, {}", settings[3], output2)).unwrap();
+    let output3 = amp3.run_snapshot().unwrap().get_output().unwrap();
 
-    let amp4 = intcode::Program::new(code, &format!("{}, {}", settings[4], output3));
-    let output4 = amp4.run_prog().get_output();
-    return output4;
+    let amp4 = intcode::Program::new(code, &format!("{}, {}", settings[4], output3)).unwrap();
+    amp4.run_snapshot().unwrap().get_output().unwrap()
 }
 
-fn run_amps_with_feedback(code: &str, settings: &[usize], input0_init: i32) -> i32 {
-    let mut amp0 = intcode::Program::new(code, &format!("{}", settings[0]));
-    let mut amp1 = intcode::Program::new(code, &format!("{}", settings[1]));
-    let mut amp2 = intcode::Program::new(code, &format!("{}", settings[2]));
-    let mut amp3 = intcode::Program::new(code, &format!("{}", settings[3]));
-    let mut amp4 = intcode::Program::new(code, &format!("{}", settings[4]));
-
-    let mut input0 = input0_init;
-
-    loop {
-        amp0.push_input(input0);
-        amp0 = amp0.run_prog();
-        if amp0.is_done() {
-            break;
-        }
-        let input1 = amp0.get_output();
-
-        amp1.push_input(input1);
-        amp1 = amp1.run_prog();
-        if amp1.is_done() {
-            break;
-        }
-        let input2 = amp1.get_output();
-
-        amp2.push_input(input2);
-        amp2 = amp2.run_prog();
-        if amp2.is_done() {
-            break;
-        }
-        let input3 = amp2.get_output();
-
-        amp3.push_input(input3);
-        amp3 = amp3.run_prog();
-        if amp3.is_done() {
-            break;
-        }
-        let input4 = amp3.get_output();
-
-        amp4.push_input(input4);
-        amp4 = amp4.run_prog();
-        if amp4.is_done() {
-            break;
-        }
-        input0 = amp4.get_output();
+fn run_amps_with_feedback(code: &str, settings: &[usize], input0_init: i128) -> i128 {
+    let mut scheduler = Scheduler::new();
+    for setting in settings {
+        scheduler.add(intcode::Program::new(code, &format!("{}", setting)).unwrap());
+    }
+    for amp in 0..settings.len() {
+        scheduler.connect(amp, (amp + 1) % settings.len());
     }
-    input0
+    scheduler.push_input(0, input0_init);
+    assert_eq!(scheduler.run().unwrap(), Outcome::AllHalted);
+    scheduler.last_value(settings.len() - 1, 0).unwrap()
 }
 
 pub fn run_part1(input7: &str) {
@@ -1,27 +1,16 @@
-#[path = "intcode.rs"]
-mod intcode;
+use advent_of_code_2019::intcode;
 
 pub fn run_part1(input9: &str) {
-    let mut prog = intcode::Program::new(input9, "1");
-    loop {
-        prog = prog.run_prog();
-        if prog.is_done() {
-            break;
-        }
-        let output = prog.get_output();
-        println!(" part 1: {}", output);
+    let mut prog = intcode::Program::new(input9, "1").unwrap();
+    for output in prog.outputs_with(|| None) {
+        println!(" part 1: {}", output.unwrap());
     }
 }
 
 pub fn run_part2(input9: &str) {
-    let mut prog = intcode::Program::new(input9, "2");
-    loop {
-        prog = prog.run_prog();
-        if prog.is_done() {
-            break;
-        }
-        let output = prog.get_output();
-        println!(" part 2: {}", output);
+    let mut prog = intcode::Program::new(input9, "2").unwrap();
+    for output in prog.outputs_with(|| None) {
+        println!(" part 2: {}", output.unwrap());
     }
 }
 
@@ -1,5 +1,4 @@
-#[path = "intcode.rs"]
-mod intcode;
+use advent_of_code_2019::intcode;
 
 use std::collections::HashSet;
 
@@ -37,7 +36,7 @@ impl Hull {
     fn new(width: usize) -> Hull {
         Hull {
             plates: vec![Color::Black; width * width],
-            width: width,
+            width,
             offset: width as i32 / 2,
         }
     }
@@ -87,7 +86,7 @@ impl Robot {
             x: 0,
             y: 0,
             direction_angle: 0,
-            program: intcode::Program::new(code, ""),
+            program: intcode::Program::new(code, "").unwrap(),
         }
     }
 
@@ -128,16 +127,15 @@ impl Robot {
         loop {
             let color_under_robot = hull.color_of_plate(self.x, self.y);
             self.program.push_input(color_under_robot as i128);
-            self.program = self.program.run_prog();
-            if self.program.is_done() {
+            let outputs = self.program.run_until_outputs(2).unwrap();
+            if outputs.len() < 2 {
                 break;
             }
-            let color_code = self.program.get_output() as i32;
+            let color_code = outputs[0] as i32;
             println!("color code {}", color_code);
             hash.insert((self.x, self.y));
             hull.paint(self.x, self.y, color_code);
-            self.program = self.program.run_prog();
-            let direction_code = self.program.get_output() as u32;
+            let direction_code = outputs[1] as u32;
             println!("direction code {}", direction_code);
             self.rotate(direction_code);
             self.move_forward();
@@ -1,5 +1,6 @@
-#[path = "intcode.rs"]
-mod intcode;
+use advent_of_code_2019::intcode;
+
+use intcode::StopReason;
 
 use std::collections::HashSet;
 
@@ -79,36 +80,24 @@ impl Game {
     fn new(code: &str, screen_width: usize, screen_height: usize) -> Game {
         Game {
             screen: Screen::new(screen_width, screen_height),
-            program: intcode::Program::new(code, ""),
+            program: intcode::Program::new(code, "").unwrap(),
         }
     }
 
     fn add_quarter(&mut self) {
-        self.program.poke(0, 2);
+        self.program.poke(0, 2).unwrap();
     }
 
     fn play(&mut self) {
         let mut num_score_bumps = 0;
+        // outputs left over from a triple that isn't finished yet
+        let mut outputs = vec![];
         loop {
-            self.program = self.program.run_prog();
-            if self.program.is_done() {
-                break;
-            } else if self.program.needs_input() {
-                let ball_x = self.screen.ball_x();
-                let paddle_x = self.screen.paddle_x();
-                if paddle_x < ball_x {
-                    self.program.push_input(1);
-                } else if paddle_x > ball_x {
-                    self.program.push_input(-1);
-                } else {
-                    self.program.push_input(0);
-                }
-            } else {
-                let x = self.program.get_output() as i32;
-                self.program = self.program.run_prog();
-                let y = self.program.get_output() as u32;
-                self.program = self.program.run_prog();
-                let tile_id = self.program.get_output() as u32;
+            let reason = self.program.run_until_blocked().unwrap();
+            outputs.extend(self.program.drain_outputs());
+            let mut triples = outputs.chunks_exact(3);
+            for triple in triples.by_ref() {
+                let (x, y, tile_id) = (triple[0] as i32, triple[1] as u32, triple[2] as u32);
                 if (x, y) == (-1, 0) {
                     num_score_bumps += 1;
                     println!(
@@ -119,6 +108,19 @@ impl Game {
                     self.screen.set_tile(x as u32, y, tile_id);
                 }
             }
+            outputs = triples.remainder().to_vec();
+            if reason == StopReason::Halted {
+                break;
+            }
+            let ball_x = self.screen.ball_x();
+            let paddle_x = self.screen.paddle_x();
+            if paddle_x < ball_x {
+                self.program.push_input(1);
+            } else if paddle_x > ball_x {
+                self.program.push_input(-1);
+            } else {
+                self.program.push_input(0);
+            }
         }
         self.screen.print();
     }
@@ -126,18 +128,14 @@ impl Game {
 pub fn step_1(input: &str) {
     let mut screen = Screen::new(50, 50);
     let mut hash = HashSet::new();
-    let mut program = intcode::Program::new(input, "");
+    let mut program = intcode::Program::new(input, "").unwrap();
 
     loop {
-        program = program.run_prog();
-        if program.is_done() {
+        let triple = program.run_until_outputs(3).unwrap();
+        if triple.len() < 3 {
             break;
         }
-        let x = program.get_output() as u32;
-        program = program.run_prog();
-        let y = program.get_output() as u32;
-        program = program.run_prog();
-        let tile_id = program.get_output() as u32;
+        let (x, y, tile_id) = (triple[0] as u32, triple[1] as u32, triple[2] as u32);
 
         println!("{} {} : {}", x, y, tile_id);
         // count block tiles
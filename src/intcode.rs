@@ -1,60 +1,649 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+pub mod ascii;
+pub mod asm;
+pub mod cache;
+pub mod cfg;
+pub mod coverage;
+pub mod custom;
+pub mod cycle;
+pub mod debugger;
+pub mod disasm;
+pub mod history;
+pub mod io;
+pub mod network;
+pub mod profile;
+pub mod scheduler;
+pub mod search;
+pub mod state;
+pub mod trace;
+pub mod watch;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntcodeError {
+    InvalidOpcode {
+        inst_ptr: usize,
+        instruction: i128,
+    },
+    InvalidMode {
+        inst_ptr: usize,
+        instruction: i128,
+        operand_index: usize,
+        mode: i128,
+    },
+    NegativeAddress {
+        inst_ptr: usize,
+        instruction: i128,
+        address: i128,
+    },
+    AddressOutOfRange {
+        inst_ptr: usize,
+        instruction: i128,
+        address: i128,
+    },
+    NegativeRelativeBase {
+        inst_ptr: usize,
+        instruction: i128,
+        relative_base: i128,
+    },
+    MissingOutput {
+        inst_ptr: usize,
+        instruction: i128,
+    },
+    // an ADD, MUL or IN result that doesn't fit the program's word type
+    Overflow {
+        inst_ptr: usize,
+        instruction: i128,
+    },
+    // a value given to poke that doesn't fit the program's word type
+    PokeOverflow {
+        address: usize,
+        value: i128,
+    },
+    // a rejected write into a cell that has already run as code
+    WriteIntoCode {
+        inst_ptr: usize,
+        instruction: i128,
+        address: usize,
+    },
+    // a custom opcode's handler writing through a parameter that isn't a
+    // Write one (operand_index counts from 1, like InvalidMode's)
+    NotAWriteParam {
+        inst_ptr: usize,
+        instruction: i128,
+        operand_index: usize,
+    },
+    // position is the index of the offending comma separated token
+    Parse {
+        position: usize,
+        token: String,
+    },
+}
+
+impl fmt::Display for IntcodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IntcodeError::InvalidOpcode {
+                inst_ptr,
+                instruction,
+            } => write!(
+                f,
+                "invalid opcode {} in instruction {} at {}",
+                instruction % 100,
+                instruction,
+                inst_ptr
+            ),
+            IntcodeError::InvalidMode {
+                inst_ptr,
+                instruction,
+                operand_index,
+                mode,
+            } => write!(
+                f,
+                "invalid mode {} for operand {} in instruction {} at {}",
+                mode, operand_index, instruction, inst_ptr
+            ),
+            IntcodeError::NegativeAddress {
+                inst_ptr,
+                instruction,
+                address,
+            } => write!(
+                f,
+                "negative address {} in instruction {} at {}",
+                address, instruction, inst_ptr
+            ),
+            IntcodeError::AddressOutOfRange {
+                inst_ptr,
+                instruction,
+                address,
+            } => write!(
+                f,
+                "address {} out of range in instruction {} at {}",
+                address, instruction, inst_ptr
+            ),
+            IntcodeError::NegativeRelativeBase {
+                inst_ptr,
+                instruction,
+                relative_base,
+            } => write!(
+                f,
+                "negative relative base {} from instruction {} at {}",
+                relative_base, instruction, inst_ptr
+            ),
+            IntcodeError::MissingOutput {
+                inst_ptr,
+                instruction,
+            } => write!(
+                f,
+                "no output available (instruction {} at {})",
+                instruction, inst_ptr
+            ),
+            IntcodeError::Overflow {
+                inst_ptr,
+                instruction,
+            } => write!(f, "overflow in instruction {} at {}", instruction, inst_ptr),
+            IntcodeError::PokeOverflow { address, value } => {
+                write!(
+                    f,
+                    "can't poke {} into {}: it doesn't fit the word type",
+                    value, address
+                )
+            }
+            IntcodeError::WriteIntoCode {
+                inst_ptr,
+                instruction,
+                address,
+            } => write!(
+                f,
+                "write into executed code at {} from instruction {} at {}",
+                address, instruction, inst_ptr
+            ),
+            IntcodeError::NotAWriteParam {
+                inst_ptr,
+                instruction,
+                operand_index,
+            } => write!(
+                f,
+                "operand {} is not a write parameter of instruction {} at {}",
+                operand_index, instruction, inst_ptr
+            ),
+            IntcodeError::Parse { position, token } => {
+                write!(f, "can't parse {:?} at position {}", token, position)
+            }
+        }
+    }
+}
+
+impl std::error::Error for IntcodeError {}
+
+// Writes this close past the end of the dense prefix grow it rather than
+// going into the sparse map.
+const DENSE_SLACK: usize = 1024;
+
+// how often run_with_deadline looks at the clock
+const DEADLINE_CHECK_STEPS: u64 = 1024;
+
+// The type memory cells are stored as. Values are widened to i128 to be
+// worked on, and ADD, MUL and IN results that don't fit back into the word
+// are an Overflow error.
+pub trait Word: Copy + Default + PartialEq + fmt::Debug + Into<i128> + TryFrom<i128> {}
+
+impl Word for i64 {}
+impl Word for i128 {}
+
+// for values already known to fit
+fn to_word<W: Word>(value: i128) -> W {
+    W::try_from(value).ok().expect("value fits the word type")
+}
+
+// Memory is a dense vector holding the loaded image (and anything written
+// just past it) plus a sparse map for far-away addresses. Every address
+// reads as 0 until written.
+#[derive(Clone, Default)]
+struct Memory<W> {
+    dense: Vec<W>,
+    sparse: HashMap<usize, W>,
+}
+
+impl<W: Word> Memory<W> {
+    fn new(image: &[W]) -> Memory<W> {
+        Memory {
+            dense: image.to_vec(),
+            sparse: HashMap::new(),
+        }
+    }
+
+    fn get(&self, address: usize) -> W {
+        match self.dense.get(address) {
+            Some(value) => *value,
+            None => self.sparse.get(&address).copied().unwrap_or_default(),
+        }
+    }
+
+    fn set(&mut self, address: usize, value: W) {
+        let dense_len = self.dense.len();
+        if address < dense_len {
+            self.dense[address] = value;
+        } else if address - dense_len < DENSE_SLACK {
+            self.dense.resize(address + 1, W::default());
+            for a in dense_len..address {
+                if let Some(v) = self.sparse.remove(&a) {
+                    self.dense[a] = v;
+                }
+            }
+            self.sparse.remove(&address);
+            self.dense[address] = value;
+        } else if value == W::default() {
+            self.sparse.remove(&address);
+        } else {
+            self.sparse.insert(address, value);
+        }
+    }
+
+    // equal as seen through get, however the cells are laid out
+    fn same_contents(&self, other: &Memory<W>) -> bool {
+        let dense_len = self.dense.len().max(other.dense.len());
+        (0..dense_len).all(|a| self.get(a) == other.get(a))
+            && self.sparse.keys().all(|a| self.get(*a) == other.get(*a))
+            && other.sparse.keys().all(|a| self.get(*a) == other.get(*a))
+    }
+}
+
+impl<W: Word> fmt::Debug for Memory<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut sparse: Vec<(&usize, &W)> = self.sparse.iter().collect();
+        sparse.sort_by_key(|(address, _)| **address);
+        write!(f, "{:?}", self.dense)?;
+        if !sparse.is_empty() {
+            write!(f, " + {:?}", sparse)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    Halted,
+    NeedsInput,
+    Output(i128),
+    // only from the run_with_budget / run_with_deadline family
+    BudgetExhausted,
+    // only from run_detecting_loops: the machine is provably stuck in a
+    // loop of length instructions without I/O, starting at start
+    Looping { start: usize, length: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Opcode {
+    Add,
+    Mul,
+    In,
+    Out,
+    Jnz,
+    Jz,
+    Lt,
+    Eq,
+    Arb,
+    Hlt,
+}
+
+pub const OPCODES: [Opcode; 10] = [
+    Opcode::Add,
+    Opcode::Mul,
+    Opcode::In,
+    Opcode::Out,
+    Opcode::Jnz,
+    Opcode::Jz,
+    Opcode::Lt,
+    Opcode::Eq,
+    Opcode::Arb,
+    Opcode::Hlt,
+];
+
+impl Opcode {
+    pub fn from_code(code: i128) -> Option<Opcode> {
+        OPCODES.iter().copied().find(|op| op.code() == code)
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        OPCODES
+            .iter()
+            .copied()
+            .find(|op| op.mnemonic().eq_ignore_ascii_case(mnemonic))
+    }
+
+    pub fn code(self) -> i128 {
+        match self {
+            Opcode::Add => 1,
+            Opcode::Mul => 2,
+            Opcode::In => 3,
+            Opcode::Out => 4,
+            Opcode::Jnz => 5,
+            Opcode::Jz => 6,
+            Opcode::Lt => 7,
+            Opcode::Eq => 8,
+            Opcode::Arb => 9,
+            Opcode::Hlt => 99,
+        }
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Opcode::Add => "ADD",
+            Opcode::Mul => "MUL",
+            Opcode::In => "IN",
+            Opcode::Out => "OUT",
+            Opcode::Jnz => "JNZ",
+            Opcode::Jz => "JZ",
+            Opcode::Lt => "LT",
+            Opcode::Eq => "EQ",
+            Opcode::Arb => "ARB",
+            Opcode::Hlt => "HLT",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            Opcode::Add | Opcode::Mul | Opcode::Lt | Opcode::Eq => 3,
+            Opcode::Jnz | Opcode::Jz => 2,
+            Opcode::In | Opcode::Out | Opcode::Arb => 1,
+            Opcode::Hlt => 0,
+        }
+    }
+
+    // index (1 based) of the parameter this opcode writes to, if any
+    pub fn write_param(self) -> Option<usize> {
+        match self {
+            Opcode::Add | Opcode::Mul | Opcode::Lt | Opcode::Eq => Some(3),
+            Opcode::In => Some(1),
+            _ => None,
+        }
+    }
+
+    pub fn is_jump(self) -> bool {
+        self == Opcode::Jnz || self == Opcode::Jz
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Position,
+    Immediate,
+    Relative,
+}
+
+impl Mode {
+    pub fn from_code(code: i128) -> Option<Mode> {
+        match code {
+            0 => Some(Mode::Position),
+            1 => Some(Mode::Immediate),
+            2 => Some(Mode::Relative),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> i128 {
+        match self {
+            Mode::Position => 0,
+            Mode::Immediate => 1,
+            Mode::Relative => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Param {
+    pub mode: Mode,
+    pub value: i128,
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mode {
+            Mode::Position => write!(f, "[{}]", self.value),
+            Mode::Immediate => write!(f, "#{}", self.value),
+            Mode::Relative if self.value < 0 => write!(f, "rb{}", self.value),
+            Mode::Relative => write!(f, "rb+{}", self.value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Instruction {
+    pub opcode: Opcode,
+    params: [Param; 3],
+}
+
+impl Instruction {
+    pub fn new(opcode: Opcode, params: &[Param]) -> Instruction {
+        assert_eq!(params.len(), opcode.arity(), "wrong number of params");
+        let mut all = [Param {
+            mode: Mode::Position,
+            value: 0,
+        }; 3];
+        all[..params.len()].copy_from_slice(params);
+        Instruction {
+            opcode,
+            params: all,
+        }
+    }
+
+    pub fn params(&self) -> &[Param] {
+        &self.params[..self.opcode.arity()]
+    }
+
+    pub fn width(&self) -> usize {
+        self.opcode.arity() + 1
+    }
+
+    // the raw instruction value, opcode plus parameter modes
+    pub fn raw(&self) -> i128 {
+        self.params()
+            .iter()
+            .rev()
+            .fold(0, |modes, param| modes * 10 + param.mode.code())
+            * 100
+            + self.opcode.code()
+    }
+
+    pub fn encode(&self) -> Vec<i128> {
+        let mut words = vec![self.raw()];
+        words.extend(self.params().iter().map(|param| param.value));
+        words
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.opcode.mnemonic())?;
+        for (i, param) in self.params().iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " " } else { ", " }, param)?;
+        }
+        Ok(())
+    }
+}
+
+// Decodes the instruction at address, reading memory through read.
+pub fn decode<F>(read: F, address: usize) -> Result<Instruction, IntcodeError>
+where
+    F: Fn(usize) -> i128,
+{
+    let instruction = read(address);
+    let opcode = match Opcode::from_code(instruction % 100) {
+        Some(opcode) => opcode,
+        None => {
+            return Err(IntcodeError::InvalidOpcode {
+                inst_ptr: address,
+                instruction,
+            })
+        }
+    };
+    let mut params = [Param {
+        mode: Mode::Position,
+        value: 0,
+    }; 3];
+    let mut modes = instruction / 100;
+    for (i, param) in params.iter_mut().enumerate().take(opcode.arity()) {
+        param.mode = match Mode::from_code(modes % 10) {
+            Some(mode) => mode,
+            None => {
+                return Err(IntcodeError::InvalidMode {
+                    inst_ptr: address,
+                    instruction,
+                    operand_index: i + 1,
+                    mode: modes % 10,
+                })
+            }
+        };
+        param.value = read(address + i + 1);
+        modes /= 10;
+    }
+    Ok(Instruction { opcode, params })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Write {
+    pub address: usize,
+    pub old: i128,
+    pub new: i128,
+}
+
+// What one executed instruction did. args holds the resolved value of each
+// read param and the resolved address of the written param.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Executed {
+    pub address: usize,
+    pub instruction: Instruction,
+    args: [i128; 3],
+    reads: [Option<usize>; 3],
+    pub write: Option<Write>,
+    pub relative_base: Option<(usize, usize)>,
+    pub input: Option<i128>,
+    pub output: Option<i128>,
+    pub next: usize,
+}
+
+impl Executed {
+    pub fn args(&self) -> &[i128] {
+        &self.args[..self.instruction.opcode.arity()]
+    }
+
+    // memory cells read through position or relative params
+    pub fn reads(&self) -> impl Iterator<Item = usize> + '_ {
+        self.reads.iter().filter_map(|read| *read)
+    }
+}
+
+// Gets a look at every instruction executed through step_observed or
+// run_observed.
+pub trait Observer<W: Word = i128> {
+    fn observe(&mut self, prog: &Program<W>, executed: &Executed);
+}
+
+impl<W: Word, T: Observer<W> + ?Sized> Observer<W> for &mut T {
+    fn observe(&mut self, prog: &Program<W>, executed: &Executed) {
+        (**self).observe(prog, executed)
+    }
+}
+
+impl<W: Word, A: Observer<W>, B: Observer<W>> Observer<W> for (A, B) {
+    fn observe(&mut self, prog: &Program<W>, executed: &Executed) {
+        self.0.observe(prog, executed);
+        self.1.observe(prog, executed);
+    }
+}
+
+impl<W: Word> Observer<W> for Vec<Executed> {
+    fn observe(&mut self, _prog: &Program<W>, executed: &Executed) {
+        self.push(*executed);
+    }
+}
+
 #[derive(Clone)]
-pub struct Program {
-    code: Vec<i128>,
+pub struct Program<W: Word = i128> {
+    memory: Memory<W>,
     inst_ptr: usize,
     done: bool,
     input_needed: bool,
     input: Vec<i128>,
     input_ptr: usize,
-    output: Option<i128>,
+    outputs: VecDeque<i128>,
     relative_base: usize,
 }
 
-impl std::fmt::Debug for Program {
+impl<W: Word> std::fmt::Debug for Program<W> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "{:?}, {}, done: {}  input_needed: {}  input: {:?}  output: {}",
-            self.code,
-            self.inst_ptr,
-            self.done,
-            self.input_needed,
-            self.input,
-            self.output.unwrap_or(-6666)
+            "{:?}, {}, done: {}  input_needed: {}  input: {:?}  outputs: {:?}",
+            self.memory, self.inst_ptr, self.done, self.input_needed, self.input, self.outputs
         )
     }
 }
 
+pub fn parse_values(s: &str) -> Result<Vec<i128>, IntcodeError> {
+    s.split(',')
+        .map(|s| s.trim())
+        .enumerate()
+        .filter(|(_, s)| !s.is_empty())
+        .map(|(position, s)| {
+            s.parse().map_err(|_| IntcodeError::Parse {
+                position,
+                token: s.to_string(),
+            })
+        })
+        .collect()
+}
+
 impl Program {
-    pub fn new(code: &str, input: &str) -> Program {
-        let code_vec = code
-            .split(',')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .map(|s| s.parse().unwrap())
-            .collect();
-        let input_vec = input
-            .split(',')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .map(|s| s.parse().unwrap())
-            .collect();
-        Program::new_from_vecs(&code_vec, &input_vec)
+    pub fn new(code: &str, input: &str) -> Result<Program, IntcodeError> {
+        Program::parse(code, input)
+    }
+
+    pub fn new_from_vecs(arr: &[i128], input: &[i128]) -> Program {
+        Program::from_words(arr, input)
     }
+}
 
-    fn new_from_vecs(arr: &Vec<i128>, input: &Vec<i128>) -> Program {
-        let mut prog = Program {
-            code: arr.clone(),
+impl<W: Word> Program<W> {
+    // Program::new for any word type, e.g. Program::<i64>::parse. Code
+    // values that don't fit the word are a parse error.
+    pub fn parse(code: &str, input: &str) -> Result<Program<W>, IntcodeError> {
+        let code = parse_values(code)?
+            .into_iter()
+            .enumerate()
+            .map(|(position, value)| {
+                W::try_from(value).map_err(|_| IntcodeError::Parse {
+                    position,
+                    token: value.to_string(),
+                })
+            })
+            .collect::<Result<Vec<W>, IntcodeError>>()?;
+        Ok(Program::from_words(&code, &parse_values(input)?))
+    }
+
+    pub fn with_io<I, O>(self, input: I, output: O) -> io::Machine<I, O, W>
+    where
+        I: io::InputSource,
+        O: io::OutputSink,
+    {
+        io::Machine::new(self, input, output)
+    }
+
+    pub fn from_words(code: &[W], input: &[i128]) -> Program<W> {
+        Program {
+            memory: Memory::new(code),
             inst_ptr: 0,
             done: false,
             input_needed: false,
-            input: input.clone(),
+            input: input.to_vec(),
             input_ptr: 0,
-            output: None,
+            outputs: VecDeque::new(),
             relative_base: 0,
-        };
-        prog.code.resize(10000, 0);
-        prog
+        }
     }
 
     pub fn push_input(&mut self, input: i128) {
@@ -62,10 +651,31 @@ impl Program {
         self.input_needed = false;
     }
 
-    pub fn get_output(&mut self) -> i128 {
-        let output = self.output;
-        self.output = None;
-        output.unwrap()
+    pub fn get_output(&mut self) -> Result<i128, IntcodeError> {
+        match self.outputs.pop_front() {
+            Some(output) => Ok(output),
+            None => Err(IntcodeError::MissingOutput {
+                inst_ptr: self.inst_ptr,
+                instruction: self.instruction(),
+            }),
+        }
+    }
+
+    pub fn inst_ptr(&self) -> usize {
+        self.inst_ptr
+    }
+
+    pub fn relative_base(&self) -> usize {
+        self.relative_base
+    }
+
+    // input that has been pushed but not read yet
+    pub fn pending_input(&self) -> &[i128] {
+        &self.input[self.input_ptr..]
+    }
+
+    pub fn queued_outputs(&self) -> Vec<i128> {
+        self.outputs.iter().copied().collect()
     }
 
     pub fn is_done(&self) -> bool {
@@ -77,324 +687,541 @@ impl Program {
     }
 
     pub fn output_ready(&self) -> bool {
-        self.output.is_some()
+        !self.outputs.is_empty()
     }
 
-    fn get_opcode(&self) -> i128 {
-        let instruction = self.code[self.inst_ptr];
-        let opcode = instruction % 100;
-        if (opcode < 0 || opcode > 9) && opcode != 99 {
-            panic!("Invalid opcode {}", opcode);
-        }
-        instruction % 100
+    pub fn drain_outputs(&mut self) -> Vec<i128> {
+        self.outputs.drain(..).collect()
     }
 
-    fn get_operand_mode(&self, operand_index: usize) -> usize {
-        let instruction = self.code[self.inst_ptr];
-        let operand_mode;
-        match operand_index {
-            1 => operand_mode = (instruction / 100) % 10,
-            2 => operand_mode = (instruction / 1000) % 10,
-            3 => operand_mode = (instruction / 10000) % 10,
-            _ => panic!("Invalid operand_index {}", operand_index),
-        }
-        if operand_mode < 0 || operand_mode > 2 {
-            panic!("Invalid operand mode {}", operand_mode);
+    // the raw instruction at inst_ptr, or 0 if inst_ptr has run off the end
+    fn instruction(&self) -> i128 {
+        self.memory.get(self.inst_ptr).into()
+    }
+
+    fn load(&self, address: usize) -> Result<i128, IntcodeError> {
+        Ok(self.memory.get(address).into())
+    }
+
+    // an ADD, MUL or IN result, if it fits the word type
+    fn checked(&self, value: Option<i128>) -> Result<i128, IntcodeError> {
+        match value {
+            Some(value) if W::try_from(value).is_ok() => Ok(value),
+            _ => Err(IntcodeError::Overflow {
+                inst_ptr: self.inst_ptr,
+                instruction: self.instruction(),
+            }),
         }
-        operand_mode as usize
     }
 
-    fn get_operand_addr(&self, operand_index: usize) -> i128 {
-        let op_mode = self.get_operand_mode(operand_index);
-        let mut op_addr = self.code[self.inst_ptr + operand_index];
-        if op_mode == 2 {
-            op_addr = (self.relative_base as i128 + op_addr) as i128
+    // the address a position or relative mode param refers to
+    fn param_addr(&self, param: Param) -> Result<usize, IntcodeError> {
+        match param.mode {
+            Mode::Relative => self.relative_addr(param.value),
+            _ => self.to_address(param.value),
         }
-        op_addr
     }
 
-    fn get_operand(&self, operand_index: usize) -> i128 {
-        let op_mode = self.get_operand_mode(operand_index);
-        let op_addr = self.get_operand_addr(operand_index);
-        if op_mode == 1 {
-            return op_addr;
-        } else {
-            return self.code[op_addr as usize];
+    // relative_base + offset as an address
+    fn relative_addr(&self, offset: i128) -> Result<usize, IntcodeError> {
+        match (self.relative_base as i128).checked_add(offset) {
+            Some(address) => self.to_address(address),
+            // past i128::MAX
+            None => Err(IntcodeError::AddressOutOfRange {
+                inst_ptr: self.inst_ptr,
+                instruction: self.instruction(),
+                address: i128::MAX,
+            }),
         }
     }
 
-    fn complete_instruction(&self) -> String {
-        let opcode = self.get_opcode();
-        match opcode {
-            1 | 2 | 7 | 8 => {
-                return format!(
-                    "{} {} {} {}",
-                    self.code[self.inst_ptr],
-                    self.code[self.inst_ptr + 1],
-                    self.code[self.inst_ptr + 2],
-                    self.code[self.inst_ptr + 3],
-                )
-            }
-            5 | 6 => {
-                return format!(
-                    "{} {} {}",
-                    self.code[self.inst_ptr],
-                    self.code[self.inst_ptr + 1],
-                    self.code[self.inst_ptr + 2]
-                )
-            }
-            3 | 4 | 9 => {
-                return format!(
-                    "{} {}",
-                    self.code[self.inst_ptr],
-                    self.code[self.inst_ptr + 1]
-                )
+    // the relative base after an ARB by offset
+    fn moved_relative_base(&self, offset: i128) -> Result<usize, IntcodeError> {
+        match (self.relative_base as i128).checked_add(offset) {
+            Some(relative_base) if relative_base >= 0 && relative_base <= usize::MAX as i128 => {
+                Ok(relative_base as usize)
             }
-            99 => return format!("{}", self.code[self.inst_ptr]),
+            relative_base => Err(IntcodeError::NegativeRelativeBase {
+                inst_ptr: self.inst_ptr,
+                instruction: self.instruction(),
+                relative_base: relative_base.unwrap_or(i128::MAX),
+            }),
+        }
+    }
+
+    fn param_value(&self, param: Param) -> Result<(i128, Option<usize>), IntcodeError> {
+        if param.mode == Mode::Immediate {
+            return Ok((param.value, None));
+        }
+        let address = self.param_addr(param)?;
+        Ok((self.load(address)?, Some(address)))
+    }
 
-            _ => return "huh???".to_string(),
+    fn to_address(&self, value: i128) -> Result<usize, IntcodeError> {
+        if value < 0 {
+            return Err(IntcodeError::NegativeAddress {
+                inst_ptr: self.inst_ptr,
+                instruction: self.instruction(),
+                address: value,
+            });
         }
+        if value > usize::MAX as i128 {
+            return Err(IntcodeError::AddressOutOfRange {
+                inst_ptr: self.inst_ptr,
+                instruction: self.instruction(),
+                address: value,
+            });
+        }
+        Ok(value as usize)
+    }
+
+    // the loaded image plus anything written close past its end
+    pub fn image(&self) -> &[W] {
+        &self.memory.dense
+    }
+
+    pub fn decode_at(&self, address: usize) -> Result<Instruction, IntcodeError> {
+        decode(|a| self.memory.get(a).into(), address)
     }
 
     pub fn peek(&self, address: usize) -> i128 {
-        self.code[address]
+        self.memory.get(address).into()
     }
 
-    pub fn poke(&mut self, address: usize, value: i128) {
-        self.code[address] = value;
+    // values that don't fit the word type are a PokeOverflow error
+    pub fn poke(&mut self, address: usize, value: i128) -> Result<(), IntcodeError> {
+        match W::try_from(value) {
+            Ok(word) => self.memory.set(address, word),
+            Err(_) => return Err(IntcodeError::PokeOverflow { address, value }),
+        }
+        Ok(())
     }
 
-    fn step_prog(&self) -> Program {
-        let mut prog = self.clone();
-        prog.done = false;
-        prog.input_needed = false;
-        let opcode = self.get_opcode();
-
-        // print!("-- --  op {} : {} : ", opcode, prog.complete_instruction());
-        match opcode {
-            1 => {
-                let op1 = prog.get_operand(1);
-                let op2 = prog.get_operand(2);
-                let res_addr = prog.get_operand_addr(3) as usize;
-                prog.code[res_addr] = op1 + op2;
-                prog.inst_ptr += 4;
-                // print!("{} + {} ({}) => addr {}", op1, op2, op1 + op2, res_addr);
+    pub fn step(&mut self) -> Result<Option<StopReason>, IntcodeError> {
+        self.step_traced().map(|(reason, _)| reason)
+    }
+
+    // Like step, but also describes what the instruction did. There is no
+    // record when the program stalls waiting for input.
+    pub fn step_traced(&mut self) -> Result<(Option<StopReason>, Option<Executed>), IntcodeError> {
+        let executed = self.preview()?;
+        Ok(self.step_previewed(executed))
+    }
+
+    // step_traced, given what preview returned for the current instruction
+    fn step_previewed(
+        &mut self,
+        executed: Option<Executed>,
+    ) -> (Option<StopReason>, Option<Executed>) {
+        self.done = false;
+        self.input_needed = false;
+        match executed {
+            Some(executed) => (self.apply(&executed), Some(executed)),
+            None => {
+                self.input_needed = true;
+                (Some(StopReason::NeedsInput), None)
             }
-            2 => {
-                let op1 = prog.get_operand(1);
-                let op2 = prog.get_operand(2);
-                let res_addr = prog.get_operand_addr(3) as usize;
-                prog.code[res_addr] = op1 * op2;
-                prog.inst_ptr += 4;
-                // print!("{} + {} ({}) => addr {}", op1, op2, op1 * op2, res_addr);
+        }
+    }
+
+    // What the next instruction would do, without doing it. None means it
+    // is an IN with no input left.
+    pub fn preview(&self) -> Result<Option<Executed>, IntcodeError> {
+        let inst = self.decode_at(self.inst_ptr)?;
+        let params = inst.params();
+        let mut executed = Executed {
+            address: self.inst_ptr,
+            instruction: inst,
+            args: [0; 3],
+            reads: [None; 3],
+            write: None,
+            relative_base: None,
+            input: None,
+            output: None,
+            next: self.inst_ptr + inst.width(),
+        };
+
+        if inst.opcode == Opcode::In && self.input_ptr >= self.input.len() {
+            return Ok(None);
+        }
+        for (i, param) in params.iter().enumerate() {
+            if inst.opcode.write_param() == Some(i + 1) {
+                executed.args[i] = self.param_addr(*param)? as i128;
+            } else {
+                let (value, read) = self.param_value(*param)?;
+                executed.args[i] = value;
+                executed.reads[i] = read;
             }
-            3 => {
-                if prog.input_ptr >= prog.input.len() {
-                    // println!("-- -- Input needed but empty");
-                    prog.input_needed = true;
-                } else {
-                    let res_addr = prog.get_operand_addr(1) as usize;
-                    let input = prog.input[prog.input_ptr];
-                    // print!("-- -- Store input {} => addr {}", input, res_addr);
-                    prog.code[res_addr] = input;
-                    prog.inst_ptr += 2;
-                    prog.input_ptr += 1;
-                }
+        }
+        let args = executed.args;
+
+        let result = match inst.opcode {
+            Opcode::Add => Some(self.checked(args[0].checked_add(args[1]))?),
+            Opcode::Mul => Some(self.checked(args[0].checked_mul(args[1]))?),
+            Opcode::Lt => Some(if args[0] < args[1] { 1 } else { 0 }),
+            Opcode::Eq => Some(if args[0] == args[1] { 1 } else { 0 }),
+            Opcode::In => {
+                let input = self.checked(Some(self.input[self.input_ptr]))?;
+                executed.input = Some(input);
+                Some(input)
             }
-            4 => {
-                let op1 = prog.get_operand(1);
-                // print!("output {}", op1);
-                prog.output = Some(op1);
-                prog.inst_ptr += 2;
+            _ => None,
+        };
+        match inst.opcode {
+            Opcode::Out => executed.output = Some(args[0]),
+            Opcode::Jnz | Opcode::Jz if (args[0] != 0) == (inst.opcode == Opcode::Jnz) => {
+                executed.next = self.to_address(args[1])?;
             }
-            5 => {
-                let op1 = prog.get_operand(1);
-                let op2 = prog.get_operand(2);
-
-                if op1 != 0 {
-                    // print!("{} != 0 so set inst_ptr to {}", op1, op2);
-                    prog.inst_ptr = op2 as usize;
-                } else {
-                    // print!("{} == 0 so add 3 to inst_ptr", op1);
-                    prog.inst_ptr += 3;
-                }
+            Opcode::Arb => {
+                let new_relative_base = self.moved_relative_base(args[0])?;
+                executed.relative_base = Some((self.relative_base, new_relative_base));
             }
-            6 => {
-                let op1 = prog.get_operand(1);
-                let op2 = prog.get_operand(2);
-                if op1 == 0 {
-                    prog.inst_ptr = op2 as usize;
-                } else {
-                    prog.inst_ptr += 3;
-                }
+            Opcode::Hlt => executed.next = self.inst_ptr,
+            _ => {}
+        }
+
+        if let Some(value) = result {
+            let address = args[inst.opcode.write_param().unwrap() - 1] as usize;
+            executed.write = Some(Write {
+                address,
+                old: self.load(address)?,
+                new: value,
+            });
+        }
+        Ok(Some(executed))
+    }
+
+    // carries out a record made by preview on the same state
+    fn apply(&mut self, executed: &Executed) -> Option<StopReason> {
+        if let Some(write) = executed.write {
+            self.memory.set(write.address, to_word(write.new));
+        }
+        if executed.input.is_some() {
+            self.input_ptr += 1;
+        }
+        if let Some((_, new)) = executed.relative_base {
+            self.relative_base = new;
+        }
+        self.inst_ptr = executed.next;
+        if executed.instruction.opcode == Opcode::Hlt {
+            self.done = true;
+            return Some(StopReason::Halted);
+        }
+        executed.output.map(StopReason::Output)
+    }
+
+    // registers, input cursor and memory contents match
+    fn same_state(&self, other: &Program<W>) -> bool {
+        self.inst_ptr == other.inst_ptr
+            && self.relative_base == other.relative_base
+            && self.input_ptr == other.input_ptr
+            && self.memory.same_contents(&other.memory)
+    }
+
+    pub fn step_observed<O>(&mut self, observer: &mut O) -> Result<Option<StopReason>, IntcodeError>
+    where
+        O: Observer<W> + ?Sized,
+    {
+        let (reason, executed) = self.step_traced()?;
+        if let Some(executed) = executed {
+            observer.observe(self, &executed);
+        }
+        Ok(reason)
+    }
+
+    pub fn run_observed<O>(&mut self, observer: &mut O) -> Result<StopReason, IntcodeError>
+    where
+        O: Observer<W> + ?Sized,
+    {
+        loop {
+            if let Some(reason) = self.step_observed(observer)? {
+                return Ok(reason);
             }
-            7 => {
-                let op1 = prog.get_operand(1);
-                let op2 = prog.get_operand(2);
-                let res_addr = prog.get_operand_addr(3) as usize;
-                if op1 < op2 {
-                    // print!("{} < {} so store 1 in addr {}", op1, op2, res_addr);
-                    prog.code[res_addr] = 1;
-                } else {
-                    // print!("{} >= {} so store 0 in addr {}", op1, op2, res_addr);
-                    prog.code[res_addr] = 0;
-                }
-                prog.inst_ptr += 4;
+        }
+    }
+
+    pub fn run_until_event(&mut self) -> Result<StopReason, IntcodeError> {
+        loop {
+            if let Some(reason) = self.step()? {
+                return Ok(reason);
+            }
+        }
+    }
+
+    // Like run_until_event, but gives up with BudgetExhausted after
+    // max_steps instructions. The program can be run again from there.
+    pub fn run_with_budget(&mut self, max_steps: u64) -> Result<StopReason, IntcodeError> {
+        for _ in 0..max_steps {
+            if let Some(reason) = self.step()? {
+                return Ok(reason);
             }
-            8 => {
-                let op1 = prog.get_operand(1);
-                let op2 = prog.get_operand(2);
-                let res_addr = prog.get_operand_addr(3) as usize;
-                prog.code[res_addr] = if op1 == op2 { 1 } else { 0 };
-                prog.inst_ptr += 4;
+        }
+        Ok(StopReason::BudgetExhausted)
+    }
+
+    // Wall clock version of run_with_budget. The clock is only checked
+    // every so many steps, so this can overrun the deadline slightly.
+    pub fn run_with_deadline(&mut self, deadline: Instant) -> Result<StopReason, IntcodeError> {
+        loop {
+            match self.run_with_budget(DEADLINE_CHECK_STEPS)? {
+                StopReason::BudgetExhausted if Instant::now() < deadline => {}
+                reason => return Ok(reason),
             }
-            9 => {
-                let op1 = prog.get_operand(1);
-                let new_relative_base = prog.relative_base as i128 + op1;
-                // print!("set relative base to {}", new_relative_base);
-                prog.relative_base = new_relative_base as usize;
-                prog.inst_ptr += 2;
+        }
+    }
+
+    pub fn run_with_timeout(&mut self, timeout: Duration) -> Result<StopReason, IntcodeError> {
+        self.run_with_deadline(Instant::now() + timeout)
+    }
+
+    // Runs until the program halts or needs input, queueing every output
+    // for get_output / drain_outputs along the way.
+    pub fn run_until_blocked(&mut self) -> Result<StopReason, IntcodeError> {
+        loop {
+            match self.run_until_event()? {
+                StopReason::Output(value) => self.outputs.push_back(value),
+                reason => return Ok(reason),
             }
-            99 => {
-                prog.done = true;
+        }
+    }
+
+    // Returns the next n outputs, taking queued ones first. Returns fewer if
+    // the program halts or needs input before producing them all.
+    pub fn run_until_outputs(&mut self, n: usize) -> Result<Vec<i128>, IntcodeError> {
+        let queued = n.min(self.outputs.len());
+        let mut outputs: Vec<i128> = self.outputs.drain(..queued).collect();
+        while outputs.len() < n {
+            match self.run_until_event()? {
+                StopReason::Output(value) => outputs.push(value),
+                _ => break,
             }
-            _ => panic!("Unknown opcode {}", opcode),
         }
-        // println!(" ");
-        return prog;
+        Ok(outputs)
     }
 
-    pub fn run_prog(&self) -> Program {
-        // println!("-- -- Starting run_prog");
+    // Iterates over outputs, calling next_input whenever the program needs
+    // input. Ends when the program halts or next_input returns None.
+    pub fn outputs_with<F>(&mut self, next_input: F) -> Outputs<'_, F, W>
+    where
+        F: FnMut() -> Option<i128>,
+    {
+        Outputs {
+            prog: self,
+            next_input,
+            finished: false,
+        }
+    }
+
+    // Runs a copy of this program to the next event and returns the copy,
+    // leaving self untouched. Any output is queued for get_output.
+    pub fn run_snapshot(&self) -> Result<Program<W>, IntcodeError> {
         let mut prog = self.clone();
+        if prog.outputs.is_empty() {
+            if let StopReason::Output(value) = prog.run_until_event()? {
+                prog.outputs.push_back(value);
+            }
+        }
+        Ok(prog)
+    }
+}
+
+pub struct Outputs<'a, F, W: Word = i128> {
+    prog: &'a mut Program<W>,
+    next_input: F,
+    finished: bool,
+}
+
+impl<'a, F, W: Word> Iterator for Outputs<'a, F, W>
+where
+    F: FnMut() -> Option<i128>,
+{
+    type Item = Result<i128, IntcodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        if let Some(value) = self.prog.outputs.pop_front() {
+            return Some(Ok(value));
+        }
+        loop {
+            match self.prog.run_until_event() {
+                Ok(StopReason::Output(value)) => return Some(Ok(value)),
+                Ok(StopReason::NeedsInput) => match (self.next_input)() {
+                    Some(input) => self.prog.push_input(input),
+                    None => break,
+                },
+                Ok(_) => break,
+                Err(err) => {
+                    self.finished = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.finished = true;
+        None
+    }
+}
+
+// fixtures shared by the tests of every intcode module
+#[cfg(test)]
+mod testing {
+    use super::{Observer, Program, StopReason};
+
+    // reads a number and outputs it doubled until it reads a 0
+    pub const DOUBLER: &str = "3, 15, 1006, 15, 14, 1002, 15, 2, 16, 4, 16, 1105, 1, 0, 99";
+
+    // Runs prog with observer watching until it halts or stops for any
+    // reason other than an output, and returns why.
+    pub fn run_observed_to_end<O: Observer>(prog: &mut Program, observer: &mut O) -> StopReason {
         loop {
-            if prog.is_done() || prog.output.is_some() || prog.needs_input() {
-                break;
+            match prog.run_observed(observer).unwrap() {
+                StopReason::Output(_) => {}
+                reason => return reason,
             }
-            prog = prog.step_prog();
         }
-        return prog;
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::testing::DOUBLER;
     use super::*;
 
     #[test]
     fn peek() {
-        let prog = Program::new("1, 1, 1, 4, 99, 5, 6, 0, 99", "");
+        let prog = Program::new("1, 1, 1, 4, 99, 5, 6, 0, 99", "").unwrap();
         assert_eq!(prog.peek(0), 1);
         assert_eq!(prog.peek(4), 99);
     }
 
     #[test]
     fn poke() {
-        let mut prog = Program::new("1, 1, 1, 4, 99, 5, 6, 0, 99", "");
-        prog.poke(2, 66);
+        let mut prog = Program::new("1, 1, 1, 4, 99, 5, 6, 0, 99", "").unwrap();
+        prog.poke(2, 66).unwrap();
         assert_eq!(prog.peek(2), 66);
+
+        let mut prog = Program::<i64>::parse("1, 1, 1, 4, 99", "").unwrap();
+        assert_eq!(
+            prog.poke(2, i128::from(i64::MAX) + 1),
+            Err(IntcodeError::PokeOverflow {
+                address: 2,
+                value: i128::from(i64::MAX) + 1
+            })
+        );
+        assert_eq!(prog.peek(2), 1);
     }
 
     #[test]
     fn step_prog() {
         let inst = "1, 9, 10, 3, 2, 3, 11, 0, 99, 30, 40, 50";
-        let prog = Program::new(inst, "0");
-        let prog = prog.step_prog();
-        assert_eq!(prog.is_done(), false);
-        assert_eq!(prog.code[3], 70);
+        let mut prog = Program::new(inst, "0").unwrap();
+        assert_eq!(prog.step().unwrap(), None);
+        assert!(!prog.is_done());
+        assert_eq!(prog.peek(3), 70);
     }
 
     #[test]
     fn run_prog_1() {
-        let prog = Program::new("1, 1, 1, 4, 99, 5, 6, 0, 99", "0");
-        let prog = prog.run_prog();
-        assert_eq!(prog.is_done(), true);
-        assert_eq!(prog.code[0], 30);
+        let mut prog = Program::new("1, 1, 1, 4, 99, 5, 6, 0, 99", "0").unwrap();
+        prog.run_until_event().unwrap();
+        assert!(prog.is_done());
+        assert_eq!(prog.peek(0), 30);
     }
 
     #[test]
     fn run_prog_2() {
-        let prog = Program::new("1, 9, 10, 3, 2, 3, 11, 0, 99, 30, 40, 50", "0");
-        let prog = prog.run_prog();
-        assert_eq!(prog.is_done(), true);
-        assert_eq!(prog.code[0], 3500);
+        let mut prog = Program::new("1, 9, 10, 3, 2, 3, 11, 0, 99, 30, 40, 50", "0").unwrap();
+        prog.run_until_event().unwrap();
+        assert!(prog.is_done());
+        assert_eq!(prog.peek(0), 3500);
     }
 
     #[test]
     fn run_prog_input() {
-        let prog = Program::new("3, 3, 99, 11", "666");
-        let prog = prog.run_prog();
-        assert_eq!(prog.is_done(), true);
-        assert_eq!(prog.code[3], 666);
+        let mut prog = Program::new("3, 3, 99, 11", "666").unwrap();
+        prog.run_until_event().unwrap();
+        assert!(prog.is_done());
+        assert_eq!(prog.peek(3), 666);
     }
 
     #[test]
     fn run_prog_2_inputs() {
-        let prog = Program::new("3, 9, 3, 10, 1, 9, 10, 0, 99, 99, 99, 99, 99", "222, 111");
-        let prog = prog.run_prog();
-        assert_eq!(prog.is_done(), true);
-        assert_eq!(prog.code[0], 333);
+        let mut prog =
+            Program::new("3, 9, 3, 10, 1, 9, 10, 0, 99, 99, 99, 99, 99", "222, 111").unwrap();
+        prog.run_until_event().unwrap();
+        assert!(prog.is_done());
+        assert_eq!(prog.peek(0), 333);
     }
 
     #[test]
     fn push_input() {
-        let mut prog = Program::new("3, 3, 99, 11", "");
+        let mut prog = Program::new("3, 3, 99, 11", "").unwrap();
         prog.push_input(666);
-        prog = prog.run_prog();
-        assert_eq!(prog.is_done(), true);
-        assert_eq!(prog.code[3], 666);
+        prog.run_until_event().unwrap();
+        assert!(prog.is_done());
+        assert_eq!(prog.peek(3), 666);
     }
 
     #[test]
     fn needs_input() {
-        let mut prog = Program::new("3, 3, 99, 11", "");
-        assert_eq!(prog.needs_input(), false);
-        prog = prog.run_prog();
-        assert_eq!(prog.is_done(), false);
-        assert_eq!(prog.needs_input(), true);
-        assert_eq!(prog.code[3], 11);
+        let mut prog = Program::new("3, 3, 99, 11", "").unwrap();
+        assert!(!prog.needs_input());
+        assert_eq!(prog.run_until_event().unwrap(), StopReason::NeedsInput);
+        assert!(!prog.is_done());
+        assert!(prog.needs_input());
+        assert_eq!(prog.peek(3), 11);
         prog.push_input(666);
-        prog = prog.run_prog();
-        assert_eq!(prog.is_done(), true);
-        assert_eq!(prog.code[3], 666);
-        assert_eq!(prog.needs_input(), false);
+        prog.run_until_event().unwrap();
+        assert!(prog.is_done());
+        assert_eq!(prog.peek(3), 666);
+        assert!(!prog.needs_input());
     }
 
     #[test]
     fn run_prog_output() {
-        let mut prog = Program::new("3, 0, 4, 0, 99", "666");
-        prog = prog.run_prog();
-        assert_eq!(prog.get_output(), 666);
+        let mut prog = Program::new("3, 0, 4, 0, 99", "666").unwrap();
+        assert_eq!(prog.run_until_event().unwrap(), StopReason::Output(666));
+        assert_eq!(prog.run_until_event().unwrap(), StopReason::Halted);
     }
 
     #[test]
     fn run_prog_modes() {
-        let prog = Program::new("1002, 4, 3, 4, 33", "666");
-        let prog = prog.run_prog();
-        assert_eq!(prog.is_done(), true);
+        let mut prog = Program::new("1002, 4, 3, 4, 33", "666").unwrap();
+        prog.run_until_event().unwrap();
+        assert!(prog.is_done());
         assert_eq!(prog.inst_ptr, 4);
     }
 
     #[test]
     fn full_1a() {
         let out = Program::new("3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8", "8")
-            .run_prog()
-            .get_output();
+            .unwrap()
+            .run_snapshot()
+            .unwrap()
+            .get_output()
+            .unwrap();
         assert_eq!(out, 1);
     }
 
     #[test]
     fn full_1b() {
         let out = Program::new("3, 3, 1107, -1, 8, 3, 4, 3, 99", "11")
-            .run_prog()
-            .get_output();
+            .unwrap()
+            .run_snapshot()
+            .unwrap()
+            .get_output()
+            .unwrap();
         assert_eq!(out, 0);
     }
     #[test]
     fn full_1c() {
         let out = Program::new("3, 3, 1107, -1, 8, 3, 4, 3, 99", "2")
-            .run_prog()
-            .get_output();
+            .unwrap()
+            .run_snapshot()
+            .unwrap()
+            .get_output()
+            .unwrap();
         assert_eq!(out, 1);
     }
 
@@ -407,42 +1234,400 @@ mod tests {
             .filter(|s| !s.is_empty())
             .map(|s| s.parse().unwrap())
             .collect();
-        let mut prog = Program::new(code, "0");
+        let mut prog = Program::new(code, "0").unwrap();
         let mut index = 0;
-        loop {
-            if prog.is_done() {
-                break;
-            }
-            prog = prog.run_prog();
-            let output = prog.get_output();
-            println!("{}", output);
-            if output == 99 {
-                break;
-            };
+        while let StopReason::Output(output) = prog.run_until_event().unwrap() {
             assert_eq!(output, code_vec[index]);
             index += 1;
         }
+        assert_eq!(index, code_vec.len());
     }
 
     #[test]
     fn opcode_9_b() {
         let code = "1102,34915192,34915192,7,4,7,99,0";
-        let output = Program::new(code, "0").run_prog().get_output();
+        let output = Program::new(code, "0")
+            .unwrap()
+            .run_snapshot()
+            .unwrap()
+            .get_output()
+            .unwrap();
         assert_eq!(format!("{}", output).len(), 16);
     }
 
     #[test]
     fn opcode_9_c() {
         let code = "104,1125899906842624,99";
-        let output = Program::new(code, "0").run_prog().get_output();
+        let output = Program::new(code, "0")
+            .unwrap()
+            .run_snapshot()
+            .unwrap()
+            .get_output()
+            .unwrap();
         assert_eq!(output, 1125899906842624);
     }
 
     #[test]
     fn test_203() {
-        let mut prog = Program::new("203,50,99", "11");
+        let mut prog = Program::new("203,50,99", "11").unwrap();
         prog.relative_base = 100;
-        prog = prog.run_prog();
-        assert_eq!(prog.code[150], 11);
+        prog.run_until_event().unwrap();
+        assert_eq!(prog.peek(150), 11);
+    }
+
+    #[test]
+    fn error_parse() {
+        let err = Program::new("1, 2, x3, 99", "").unwrap_err();
+        assert_eq!(
+            err,
+            IntcodeError::Parse {
+                position: 2,
+                token: "x3".to_string()
+            }
+        );
+        assert!(Program::new("99", "1, ,y").is_err());
+    }
+
+    #[test]
+    fn error_invalid_opcode() {
+        let err = Program::new("1101, 1, 1, 5, 42, 0", "")
+            .unwrap()
+            .run_until_event()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            IntcodeError::InvalidOpcode {
+                inst_ptr: 4,
+                instruction: 42
+            }
+        );
+    }
+
+    #[test]
+    fn error_invalid_mode() {
+        let err = Program::new("301, 1, 1, 0, 99", "")
+            .unwrap()
+            .step()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            IntcodeError::InvalidMode {
+                inst_ptr: 0,
+                instruction: 301,
+                operand_index: 1,
+                mode: 3
+            }
+        );
+    }
+
+    #[test]
+    fn error_negative_address() {
+        let err = Program::new("1, -1, 0, 0, 99", "")
+            .unwrap()
+            .step()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            IntcodeError::NegativeAddress {
+                inst_ptr: 0,
+                instruction: 1,
+                address: -1
+            }
+        );
+    }
+
+    #[test]
+    fn error_negative_relative_base() {
+        let err = Program::new("109, -5, 99", "").unwrap().step().unwrap_err();
+        assert_eq!(
+            err,
+            IntcodeError::NegativeRelativeBase {
+                inst_ptr: 0,
+                instruction: 109,
+                relative_base: -5
+            }
+        );
+    }
+
+    #[test]
+    fn relative_base_overflow() {
+        let code = format!("109, 1, 204, {}, 99", i128::MAX);
+        let err = Program::new(&code, "")
+            .unwrap()
+            .run_until_event()
+            .unwrap_err();
+        assert_eq!(
+            err,
+            IntcodeError::AddressOutOfRange {
+                inst_ptr: 2,
+                instruction: 204,
+                address: i128::MAX
+            }
+        );
+        let code = format!("109, 1, 109, {}, 99", i128::MAX);
+        let err = Program::new(&code, "")
+            .unwrap()
+            .run_until_event()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            IntcodeError::NegativeRelativeBase { inst_ptr: 2, .. }
+        ));
+    }
+
+    #[test]
+    fn error_missing_output() {
+        let mut prog = Program::new("99", "").unwrap().run_snapshot().unwrap();
+        assert_eq!(
+            prog.get_output(),
+            Err(IntcodeError::MissingOutput {
+                inst_ptr: 0,
+                instruction: 99
+            })
+        );
+    }
+
+    #[test]
+    fn memory_grows_on_demand() {
+        let mut prog = Program::new("99", "").unwrap();
+        assert_eq!(prog.peek(5), 0);
+        prog.poke(5, 7).unwrap();
+        assert_eq!(prog.peek(5), 7);
+        assert_eq!(prog.memory.dense.len(), 6);
+        prog.poke(1_000_000_000, 8).unwrap();
+        assert_eq!(prog.peek(1_000_000_000), 8);
+        assert_eq!(prog.memory.dense.len(), 6);
+        assert_eq!(prog.peek(999_999_999), 0);
+    }
+
+    #[test]
+    fn memory_sparse_cells_move_into_dense() {
+        let mut memory: Memory<i128> = Memory::new(&[1, 2, 3]);
+        memory.set(3 + DENSE_SLACK + 10, 42);
+        memory.set(3 + DENSE_SLACK + 20, 43);
+        assert_eq!(memory.sparse.len(), 2);
+        memory.set(3 + DENSE_SLACK - 1, 1);
+        memory.set(3 + DENSE_SLACK + 15, 2);
+        assert_eq!(memory.sparse.len(), 1);
+        assert_eq!(memory.get(3 + DENSE_SLACK + 10), 42);
+        assert_eq!(memory.get(3 + DENSE_SLACK + 20), 43);
+        assert_eq!(memory.dense.len(), 3 + DENSE_SLACK + 16);
+    }
+
+    #[test]
+    fn far_relative_write() {
+        let code = "109, 20000000, 21101, 3, 4, 5, 204, 5, 99";
+        let mut prog = Program::new(code, "").unwrap();
+        assert_eq!(prog.run_until_event().unwrap(), StopReason::Output(7));
+        assert_eq!(prog.peek(20_000_005), 7);
+    }
+
+    #[test]
+    fn run_until_event() {
+        let mut prog = Program::new("3, 9, 4, 9, 4, 9, 99, 0, 0, 0", "").unwrap();
+        assert_eq!(prog.run_until_event().unwrap(), StopReason::NeedsInput);
+        assert!(prog.needs_input());
+        assert_eq!(prog.run_until_event().unwrap(), StopReason::NeedsInput);
+        prog.push_input(5);
+        assert_eq!(prog.run_until_event().unwrap(), StopReason::Output(5));
+        assert_eq!(prog.run_until_event().unwrap(), StopReason::Output(5));
+        assert_eq!(prog.run_until_event().unwrap(), StopReason::Halted);
+        assert!(prog.is_done());
+        assert_eq!(prog.run_until_event().unwrap(), StopReason::Halted);
+    }
+
+    #[test]
+    fn run_with_budget() {
+        // counts up in [9] forever
+        let mut prog = Program::new("1001, 9, 1, 9, 1105, 1, 0, 99, 99, 0", "").unwrap();
+        assert_eq!(
+            prog.run_with_budget(5).unwrap(),
+            StopReason::BudgetExhausted
+        );
+        assert_eq!((prog.inst_ptr(), prog.peek(9)), (4, 3));
+        assert_eq!(
+            prog.run_with_budget(2).unwrap(),
+            StopReason::BudgetExhausted
+        );
+        assert_eq!((prog.inst_ptr(), prog.peek(9)), (4, 4));
+
+        let mut prog = Program::new("104, 1, 99", "").unwrap();
+        assert_eq!(
+            prog.run_with_budget(0).unwrap(),
+            StopReason::BudgetExhausted
+        );
+        assert_eq!(prog.run_with_budget(10).unwrap(), StopReason::Output(1));
+        assert_eq!(prog.run_with_budget(10).unwrap(), StopReason::Halted);
+    }
+
+    #[test]
+    fn run_with_timeout() {
+        let mut prog = Program::new("1001, 9, 1, 9, 1105, 1, 0, 99, 99, 0", "").unwrap();
+        assert_eq!(
+            prog.run_with_timeout(Duration::from_millis(20)).unwrap(),
+            StopReason::BudgetExhausted
+        );
+        assert!(prog.peek(9) > 0);
+        assert!(!prog.is_done());
+
+        let mut prog = Program::new("3, 0, 99", "").unwrap();
+        assert_eq!(
+            prog.run_with_timeout(Duration::from_secs(5)).unwrap(),
+            StopReason::NeedsInput
+        );
+    }
+
+    #[test]
+    fn run_snapshot_leaves_original() {
+        let prog = Program::new("3, 0, 4, 0, 99", "7").unwrap();
+        let mut snapshot = prog.run_snapshot().unwrap();
+        assert_eq!(snapshot.get_output().unwrap(), 7);
+        assert_eq!(prog.inst_ptr, 0);
+        assert_eq!(prog.peek(0), 3);
+        assert_eq!(snapshot.peek(0), 7);
+    }
+
+    #[test]
+    fn error_leaves_state_untouched() {
+        let mut prog = Program::new("1, 0, 0, 0, 1, -1, 0, 0, 99", "").unwrap();
+        prog.step().unwrap();
+        assert!(prog.step().is_err());
+        assert_eq!(prog.inst_ptr, 4);
+        assert_eq!(prog.peek(0), 2);
+    }
+
+    #[test]
+    fn output_queue() {
+        let mut prog = Program::new("104, 1, 104, 2, 3, 0, 104, 3, 99", "").unwrap();
+        assert_eq!(prog.run_until_blocked().unwrap(), StopReason::NeedsInput);
+        assert!(prog.output_ready());
+        assert_eq!(prog.get_output().unwrap(), 1);
+        prog.push_input(0);
+        assert_eq!(prog.run_until_blocked().unwrap(), StopReason::Halted);
+        assert_eq!(prog.drain_outputs(), vec![2, 3]);
+        assert!(!prog.output_ready());
+    }
+
+    #[test]
+    fn run_until_outputs() {
+        let mut prog = Program::new("104, 1, 104, 2, 104, 3, 104, 4, 99", "").unwrap();
+        assert_eq!(prog.run_until_outputs(3).unwrap(), vec![1, 2, 3]);
+        assert_eq!(prog.run_until_outputs(3).unwrap(), vec![4]);
+        assert!(prog.is_done());
+    }
+
+    #[test]
+    fn run_until_outputs_takes_queued_first() {
+        let mut prog = Program::new("104, 1, 3, 0, 104, 2, 104, 3, 99", "").unwrap();
+        prog.run_until_blocked().unwrap();
+        prog.push_input(5);
+        assert_eq!(prog.run_until_outputs(2).unwrap(), vec![1, 2]);
+        assert_eq!(prog.drain_outputs(), Vec::<i128>::new());
+    }
+
+    #[test]
+    fn outputs_with() {
+        let mut prog = Program::new(DOUBLER, "").unwrap();
+        let mut inputs = vec![3, 2, 1, 0].into_iter();
+        let outputs: Vec<i128> = prog
+            .outputs_with(|| inputs.next())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(outputs, vec![6, 4, 2]);
+        assert!(prog.is_done());
+    }
+
+    #[test]
+    fn outputs_with_starved() {
+        let mut prog = Program::new("104, 1, 3, 0, 104, 2, 99", "").unwrap();
+        let outputs: Vec<_> = prog.outputs_with(|| None).collect();
+        assert_eq!(outputs, vec![Ok(1)]);
+        assert!(prog.needs_input());
+    }
+
+    #[test]
+    fn decode_instruction() {
+        let prog = Program::new("21101, 0, -37, 5, 99", "").unwrap();
+        let inst = prog.decode_at(0).unwrap();
+        assert_eq!(inst.opcode, Opcode::Add);
+        assert_eq!(format!("{}", inst), "ADD #0, #-37, rb+5");
+        assert_eq!(inst.width(), 4);
+        assert_eq!(inst.encode(), vec![21101, 0, -37, 5]);
+        assert_eq!(format!("{}", prog.decode_at(4).unwrap()), "HLT");
+        assert!(prog.decode_at(1).is_err());
+    }
+
+    #[test]
+    fn decode_bad_mode() {
+        let prog = Program::new("304, 1", "").unwrap();
+        assert_eq!(
+            prog.decode_at(0),
+            Err(IntcodeError::InvalidMode {
+                inst_ptr: 0,
+                instruction: 304,
+                operand_index: 1,
+                mode: 3
+            })
+        );
+    }
+
+    #[test]
+    fn opcode_names() {
+        for op in OPCODES.iter() {
+            assert_eq!(Opcode::from_code(op.code()), Some(*op));
+            assert_eq!(Opcode::from_mnemonic(op.mnemonic()), Some(*op));
+        }
+        assert_eq!(Opcode::from_mnemonic("jnz"), Some(Opcode::Jnz));
+        assert_eq!(Opcode::from_code(0), None);
+    }
+
+    #[test]
+    fn i64_words() {
+        let boost = include_str!("../data/input_09.txt");
+        let mut prog = Program::<i64>::parse(boost, "1").unwrap();
+        prog.run_until_blocked().unwrap();
+        assert_eq!(prog.drain_outputs(), vec![4_261_108_180]);
+        assert_eq!(
+            Program::<i64>::parse("1, 9223372036854775808", "").unwrap_err(),
+            IntcodeError::Parse {
+                position: 1,
+                token: "9223372036854775808".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn overflow() {
+        let mut prog =
+            Program::<i64>::parse("1102, 4611686018427387904, 2, 7, 99, 0, 0, 0", "").unwrap();
+        let err = prog.run_until_event().unwrap_err();
+        assert_eq!(
+            err,
+            IntcodeError::Overflow {
+                inst_ptr: 0,
+                instruction: 1102
+            }
+        );
+        assert_eq!(err.to_string(), "overflow in instruction 1102 at 0");
+        assert_eq!((prog.inst_ptr(), prog.peek(7)), (0, 0));
+
+        // the same multiply fits an i128
+        let mut prog = Program::new("1102, 4611686018427387904, 2, 7, 99, 0, 0, 0", "").unwrap();
+        assert_eq!(prog.run_until_event().unwrap(), StopReason::Halted);
+        assert_eq!(prog.peek(7), 1 << 63);
+
+        let code = format!("1101, {}, 1, 0, 99", i128::MAX);
+        let mut prog = Program::new(&code, "").unwrap();
+        assert!(matches!(
+            prog.run_until_event(),
+            Err(IntcodeError::Overflow { inst_ptr: 0, .. })
+        ));
+
+        let mut prog = Program::<i64>::parse("3, 0, 99", "").unwrap();
+        prog.push_input(i128::from(i64::MAX) + 1);
+        assert!(matches!(
+            prog.run_until_event(),
+            Err(IntcodeError::Overflow { inst_ptr: 0, .. })
+        ));
     }
 }
@@ -0,0 +1,164 @@
+use super::{IntcodeError, Program, StopReason, Word};
+
+// Helpers for programs that talk in ASCII. Input goes in a line at a time
+// and output comes back as text:
+//
+//     prog.push_line("north");
+//     print!("{}", prog.read_all_text()?);
+//     if prog.at_prompt() {
+//         ...
+//     }
+//
+// Outputs that aren't character codes (usually the answer) end the text
+// and stay queued until read_value takes them:
+//
+//     let report = prog.read_all_text()?;
+//     let answer = prog.read_value()?;
+
+// value as a character, if it is an ASCII character code
+pub fn as_char(value: i128) -> Option<char> {
+    match value {
+        0..=127 => Some(value as u8 as char),
+        _ => None,
+    }
+}
+
+impl<W: Word> Program<W> {
+    // queues line's characters followed by a newline
+    pub fn push_line(&mut self, line: &str) {
+        for byte in line.bytes() {
+            self.push_input(i128::from(byte));
+        }
+        self.push_input(10);
+    }
+
+    // the next queued output, or else the next one the program makes
+    fn next_output(&mut self) -> Result<Option<i128>, IntcodeError> {
+        if let Some(value) = self.outputs.pop_front() {
+            return Ok(Some(value));
+        }
+        match self.run_until_event()? {
+            StopReason::Output(value) => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    // The next output as a character. None if the program stops to wait
+    // for input or halts, or if the output isn't a character code, which
+    // is put back on the queue.
+    fn next_char(&mut self) -> Result<Option<char>, IntcodeError> {
+        let value = match self.next_output()? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let c = as_char(value);
+        if c.is_none() {
+            self.outputs.push_front(value);
+        }
+        Ok(c)
+    }
+
+    // Returns the text up to the next newline (which is dropped), or
+    // whatever there is when the program stops to wait for input or halts,
+    // or outputs something that isn't a character. Queued outputs are read
+    // first.
+    pub fn read_text(&mut self) -> Result<String, IntcodeError> {
+        let mut text = String::new();
+        while let Some(c) = self.next_char()? {
+            if c == '\n' {
+                break;
+            }
+            text.push(c);
+        }
+        Ok(text)
+    }
+
+    // All the text until the program waits for input, halts or outputs
+    // something that isn't a character, newlines included.
+    pub fn read_all_text(&mut self) -> Result<String, IntcodeError> {
+        let mut text = String::new();
+        while let Some(c) = self.next_char()? {
+            text.push(c);
+        }
+        Ok(text)
+    }
+
+    // Takes the next output if it isn't a character code. A character is
+    // left for the read_text functions.
+    pub fn read_value(&mut self) -> Result<Option<i128>, IntcodeError> {
+        let value = match self.next_output()? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if as_char(value).is_some() {
+            self.outputs.push_front(value);
+            return Ok(None);
+        }
+        Ok(Some(value))
+    }
+
+    // The program is waiting for input and every output has been read, so
+    // whatever text came last was a prompt.
+    pub fn at_prompt(&self) -> bool {
+        self.input_needed && self.outputs.is_empty() && self.pending_input().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // prints "Name?\n> ", then echoes what it reads until it reads a '.',
+    // when it outputs 1234 and halts
+    fn greeter() -> Program {
+        let mut code: Vec<i128> = vec![];
+        for byte in "Name?\n> ".bytes() {
+            code.extend(&[104, i128::from(byte)]);
+        }
+        let start = code.len() as i128;
+        let (buf, flag) = (start + 19, start + 20);
+        code.extend(&[3, buf, 1008, buf, 46, flag, 1005, flag, start + 14]);
+        code.extend(&[4, buf, 1105, 1, start]);
+        code.extend(&[104, 1234, 104, 10, 99, 0, 0]);
+        Program::new_from_vecs(&code, &[])
+    }
+
+    #[test]
+    fn prompt_and_lines() {
+        let mut prog = greeter();
+        assert_eq!(prog.read_text().unwrap(), "Name?");
+        assert!(!prog.at_prompt());
+        assert_eq!(prog.read_text().unwrap(), "> ");
+        assert!(prog.at_prompt() && prog.needs_input());
+
+        prog.push_line("bob");
+        assert!(!prog.at_prompt());
+        assert_eq!(prog.read_text().unwrap(), "bob");
+        // it hasn't got back to the IN yet
+        assert!(!prog.at_prompt());
+        assert_eq!(prog.read_text().unwrap(), "");
+        assert!(prog.at_prompt());
+        prog.push_line("alice");
+        assert_eq!(prog.read_all_text().unwrap(), "alice\n");
+        assert!(prog.at_prompt());
+
+        prog.push_line(".");
+        assert_eq!(prog.read_all_text().unwrap(), "");
+        assert_eq!(prog.read_text().unwrap(), "");
+        assert_eq!(prog.read_value().unwrap(), Some(1234));
+        assert_eq!(prog.read_value().unwrap(), None);
+        assert_eq!(prog.read_all_text().unwrap(), "\n");
+        assert!(prog.is_done() && !prog.at_prompt());
+        assert_eq!(prog.read_text().unwrap(), "");
+        assert_eq!(prog.read_value().unwrap(), None);
+    }
+
+    #[test]
+    fn queued_outputs_first() {
+        let mut prog = greeter();
+        prog.run_until_blocked().unwrap();
+        assert!(prog.needs_input() && !prog.at_prompt());
+        assert_eq!(prog.read_all_text().unwrap(), "Name?\n> ");
+        assert!(prog.at_prompt());
+    }
+}
@@ -0,0 +1,358 @@
+use super::{Instruction, Mode, Opcode, Param};
+
+use std::collections::HashMap;
+use std::fmt;
+
+// Assembles mnemonic source into a program image.
+//
+//     ; comments run to the end of the line
+//     start:  IN [n]              ; [x] position, #x immediate, rb+x relative
+//             JZ [n], #done       ; labels work anywhere a number does
+//             OUT [n]
+//             JNZ #1, #start
+//     done:   HLT
+//     n:      .data 0             ; raw values
+//     buf:    .zero 4             ; n zero cells
+//
+// A number followed by a colon at the start of a line is an address check,
+// so disassembler listings can be assembled again.
+
+// the most cells a program, or one .zero, can take up
+const MAX_CELLS: usize = 1_000_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(i128),
+    Label(String, i128),
+}
+
+#[derive(Debug)]
+enum Statement {
+    Instruction(Opcode, Vec<(Mode, Expr)>),
+    Data(Vec<Expr>),
+    Zero(usize),
+}
+
+impl Statement {
+    fn width(&self) -> usize {
+        match self {
+            Statement::Instruction(opcode, _) => opcode.arity() + 1,
+            Statement::Data(values) => values.len(),
+            Statement::Zero(n) => *n,
+        }
+    }
+}
+
+fn is_label(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_expr(s: &str) -> Result<Expr, String> {
+    let s = s.trim();
+    if let Ok(value) = s.parse() {
+        return Ok(Expr::Number(value));
+    }
+    let (name, offset) = match s.find(['+', '-']) {
+        Some(i) => {
+            let offset: i128 = s[i + 1..]
+                .trim()
+                .parse()
+                .map_err(|_| format!("bad offset in {:?}", s))?;
+            let sign = if &s[i..=i] == "-" { -1 } else { 1 };
+            (s[..i].trim(), sign * offset)
+        }
+        None => (s, 0),
+    };
+    if is_label(name) {
+        Ok(Expr::Label(name.to_string(), offset))
+    } else {
+        Err(format!("bad value {:?}", s))
+    }
+}
+
+fn parse_operand(s: &str) -> Result<(Mode, Expr), String> {
+    let s = s.trim();
+    if let Some(rest) = s.strip_prefix('#') {
+        Ok((Mode::Immediate, parse_expr(rest)?))
+    } else if s.starts_with('[') && s.ends_with(']') {
+        Ok((Mode::Position, parse_expr(&s[1..s.len() - 1])?))
+    } else if let Some(rest) = s.strip_prefix("rb") {
+        let rest = rest.trim();
+        if rest.is_empty() {
+            Ok((Mode::Relative, Expr::Number(0)))
+        } else if let Some(offset) = rest.strip_prefix('+') {
+            Ok((Mode::Relative, parse_expr(offset)?))
+        } else if rest.starts_with('-') {
+            match parse_expr(rest)? {
+                Expr::Number(value) => Ok((Mode::Relative, Expr::Number(value))),
+                _ => Err(format!("bad relative operand {:?}", s)),
+            }
+        } else {
+            Err(format!("bad relative operand {:?}", s))
+        }
+    } else {
+        Err(format!("operand {:?} needs a mode: [x], #x or rb+x", s))
+    }
+}
+
+fn split_list(s: &str) -> Vec<&str> {
+    if s.trim().is_empty() {
+        vec![]
+    } else {
+        s.split(',').map(|s| s.trim()).collect()
+    }
+}
+
+fn parse_statement(text: &str) -> Result<Statement, String> {
+    let (word, rest) = match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], &text[i..]),
+        None => (text, ""),
+    };
+    match word {
+        ".data" => {
+            let values = split_list(rest)
+                .into_iter()
+                .map(parse_expr)
+                .collect::<Result<Vec<Expr>, String>>()?;
+            if values.is_empty() {
+                return Err(".data needs at least one value".to_string());
+            }
+            Ok(Statement::Data(values))
+        }
+        ".zero" => {
+            let count: usize = rest
+                .trim()
+                .parse()
+                .map_err(|_| format!("bad .zero count {:?}", rest.trim()))?;
+            if count > MAX_CELLS {
+                return Err(format!(".zero count {} is more than {}", count, MAX_CELLS));
+            }
+            Ok(Statement::Zero(count))
+        }
+        _ if word.starts_with('.') => Err(format!("unknown directive {}", word)),
+        _ => {
+            let opcode = Opcode::from_mnemonic(word).ok_or(format!("unknown mnemonic {}", word))?;
+            let operands = split_list(rest)
+                .into_iter()
+                .map(parse_operand)
+                .collect::<Result<Vec<(Mode, Expr)>, String>>()?;
+            if operands.len() != opcode.arity() {
+                return Err(format!(
+                    "{} takes {} operands, got {}",
+                    opcode.mnemonic(),
+                    opcode.arity(),
+                    operands.len()
+                ));
+            }
+            if let Some(index) = opcode.write_param() {
+                if operands[index - 1].0 == Mode::Immediate {
+                    return Err(format!("{} can't write to an immediate", opcode.mnemonic()));
+                }
+            }
+            Ok(Statement::Instruction(opcode, operands))
+        }
+    }
+}
+
+fn resolve(expr: &Expr, labels: &HashMap<String, usize>) -> Result<i128, String> {
+    match expr {
+        Expr::Number(value) => Ok(*value),
+        Expr::Label(name, offset) => match labels.get(name) {
+            Some(address) => Ok(*address as i128 + offset),
+            None => Err(format!("undefined label {}", name)),
+        },
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Vec<i128>, AsmError> {
+    let mut labels: HashMap<String, usize> = HashMap::new();
+    let mut statements = vec![];
+    let mut address = 0;
+
+    for (i, line) in source.lines().enumerate() {
+        let error = |message: String| AsmError {
+            line: i + 1,
+            message,
+        };
+        let mut text = match line.find(';') {
+            Some(i) => &line[..i],
+            None => line,
+        }
+        .trim();
+        while let Some(colon) = text.find(':') {
+            let name = text[..colon].trim();
+            if let Ok(expected) = name.parse::<usize>() {
+                if expected != address {
+                    return Err(error(format!(
+                        "address is {} but line says {}",
+                        address, expected
+                    )));
+                }
+            } else if is_label(name) {
+                if labels.insert(name.to_string(), address).is_some() {
+                    return Err(error(format!("duplicate label {}", name)));
+                }
+            } else {
+                break;
+            }
+            text = text[colon + 1..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let statement = parse_statement(text).map_err(error)?;
+        address = match address.checked_add(statement.width()) {
+            Some(next) if next <= MAX_CELLS => next,
+            _ => return Err(error(format!("program is more than {} cells", MAX_CELLS))),
+        };
+        statements.push((i + 1, statement));
+    }
+
+    let mut image = Vec::with_capacity(address);
+    for (line, statement) in statements {
+        let error = |message: String| AsmError { line, message };
+        match statement {
+            Statement::Instruction(opcode, operands) => {
+                let params = operands
+                    .iter()
+                    .map(|(mode, expr)| {
+                        resolve(expr, &labels).map(|value| Param { mode: *mode, value })
+                    })
+                    .collect::<Result<Vec<Param>, String>>()
+                    .map_err(error)?;
+                image.extend(Instruction::new(opcode, &params).encode());
+            }
+            Statement::Data(values) => {
+                for expr in values.iter() {
+                    image.push(resolve(expr, &labels).map_err(error)?);
+                }
+            }
+            Statement::Zero(n) => image.resize(image.len() + n, 0),
+        }
+    }
+    Ok(image)
+}
+
+// the comma separated form Program::new takes
+pub fn to_code_string(image: &[i128]) -> String {
+    image
+        .iter()
+        .map(|value| format!("{}", value))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::disasm::disassemble;
+    use super::super::{parse_values, Program, StopReason};
+
+    #[test]
+    fn simple() {
+        let image =
+            assemble("ADD [9], [10], [3]\nMUL [3], [11], [0]\nHLT\n.data 30, 40, 50").unwrap();
+        assert_eq!(
+            to_code_string(&image),
+            "1,9,10,3,2,3,11,0,99,30,40,50".to_string()
+        );
+    }
+
+    #[test]
+    fn labels_and_modes() {
+        let source = "
+            ; echo numbers until a 0
+            start:  IN [n]
+                    JZ [n], #done
+                    OUT [n]
+                    JNZ #1, #start
+            done:   ARB #n
+                    OUT rb+1        ; first cell of buf
+                    OUT rb
+                    HLT
+            n:      .data 0
+            buf:    .zero 2
+                    .data buf, n-1
+        ";
+        let image = assemble(source).unwrap();
+        assert_eq!(
+            image,
+            vec![
+                3, 17, 1006, 17, 10, 4, 17, 1105, 1, 0, 109, 17, 204, 1, 204, 0, 99, 0, 0, 0, 18,
+                16
+            ]
+        );
+        let mut prog = Program::new(&to_code_string(&image), "7, 0").unwrap();
+        assert_eq!(prog.run_until_blocked().unwrap(), StopReason::Halted);
+        assert_eq!(prog.drain_outputs(), vec![7, 0, 0]);
+    }
+
+    #[test]
+    fn round_trip_through_disassembler() {
+        let code = "109,1,204,-1,1001,100,1,100,1008,100,16,101,1006,101,0,99";
+        let image = parse_values(code).unwrap();
+        let listing = format!("{}", disassemble(&image));
+        assert_eq!(assemble(&listing).unwrap(), image);
+
+        let image = parse_values("3, 12, 1006, 12, 9, 4, 12, 99, 7, 104, 0, 99, 0").unwrap();
+        let listing = format!("{}", disassemble(&image));
+        assert_eq!(assemble(&listing).unwrap(), image);
+    }
+
+    #[test]
+    fn errors() {
+        let err = |source: &str| assemble(source).unwrap_err();
+        assert_eq!(
+            err("HLT\nFOO #1"),
+            AsmError {
+                line: 2,
+                message: "unknown mnemonic FOO".to_string()
+            }
+        );
+        assert_eq!(err("\n\nADD #1, #2").line, 3);
+        assert_eq!(
+            err("ADD #1, #2, #3").message,
+            "ADD can't write to an immediate"
+        );
+        assert_eq!(err("OUT 5").line, 1);
+        assert_eq!(err("OUT [nowhere]").message, "undefined label nowhere");
+        assert_eq!(err("a: HLT\na: HLT").message, "duplicate label a");
+        assert_eq!(err("HLT\n0: HLT").message, "address is 1 but line says 0");
+        assert_eq!(err(".data").message, ".data needs at least one value");
+        assert_eq!(err(".org 5").message, "unknown directive .org");
+        assert_eq!(err("OUT #1x").line, 1);
+        assert_eq!(
+            err(".zero 99999999999").message,
+            ".zero count 99999999999 is more than 1000000"
+        );
+        let too_big = err(".zero 1000000\n.zero 1000000\nHLT");
+        assert_eq!(
+            (too_big.line, too_big.message.as_str()),
+            (2, "program is more than 1000000 cells")
+        );
+        assert_eq!(
+            format!("{}", err("JNZ #1")),
+            "line 1: JNZ takes 2 operands, got 1"
+        );
+    }
+}
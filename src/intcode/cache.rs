@@ -0,0 +1,361 @@
+use super::{Instruction, IntcodeError, Mode, Opcode, Param, Program, StopReason};
+
+// A faster engine for long runs. Program::step decodes every instruction
+// from memory each time it runs; this decodes each address once into an
+// Op and keeps it until a write lands on one of its cells, so
+// self-modifying programs still behave exactly as under Program::step.
+//
+//     let mut cached = Cached::new(prog);
+//     let outputs = cached.run_until_blocked()?;
+//     let prog = cached.into_program();
+//
+// On the day_09 BOOST program part 2 this is several times faster; see
+// bench_boost below (cargo test --release -- --ignored bench_boost).
+//
+// Only i128 programs can be cached; other word types run on Program::step.
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Imm(i128),
+    Pos(usize),
+    Rel(i128),
+    // a position that is not a valid address, an error if it is ever used
+    Bad(i128),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Add(Operand, Operand, Operand),
+    Mul(Operand, Operand, Operand),
+    In(Operand),
+    Out(Operand),
+    Jnz(Operand, Operand),
+    Jz(Operand, Operand),
+    Lt(Operand, Operand, Operand),
+    Eq(Operand, Operand, Operand),
+    Arb(Operand),
+    Hlt,
+}
+
+// instructions are at most this many cells wide
+const MAX_WIDTH: usize = 4;
+
+impl Op {
+    fn new(prog: &Program, inst: &Instruction) -> Op {
+        let operand = |param: Param| match param.mode {
+            Mode::Immediate => Operand::Imm(param.value),
+            Mode::Relative => Operand::Rel(param.value),
+            Mode::Position => match prog.to_address(param.value) {
+                Ok(address) => Operand::Pos(address),
+                Err(_) => Operand::Bad(param.value),
+            },
+        };
+        let p = inst.params();
+        match inst.opcode {
+            Opcode::Add => Op::Add(operand(p[0]), operand(p[1]), operand(p[2])),
+            Opcode::Mul => Op::Mul(operand(p[0]), operand(p[1]), operand(p[2])),
+            Opcode::In => Op::In(operand(p[0])),
+            Opcode::Out => Op::Out(operand(p[0])),
+            Opcode::Jnz => Op::Jnz(operand(p[0]), operand(p[1])),
+            Opcode::Jz => Op::Jz(operand(p[0]), operand(p[1])),
+            Opcode::Lt => Op::Lt(operand(p[0]), operand(p[1]), operand(p[2])),
+            Opcode::Eq => Op::Eq(operand(p[0]), operand(p[1]), operand(p[2])),
+            Opcode::Arb => Op::Arb(operand(p[0])),
+            Opcode::Hlt => Op::Hlt,
+        }
+    }
+
+    fn width(&self) -> usize {
+        match self {
+            Op::Add(..) | Op::Mul(..) | Op::Lt(..) | Op::Eq(..) => 4,
+            Op::Jnz(..) | Op::Jz(..) => 3,
+            Op::In(_) | Op::Out(_) | Op::Arb(_) => 2,
+            Op::Hlt => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cached {
+    program: Program,
+    ops: Vec<Option<Op>>,
+    decodes: u64,
+}
+
+impl Cached {
+    pub fn new(program: Program) -> Cached {
+        Cached {
+            ops: vec![None; program.image().len()],
+            program,
+            decodes: 0,
+        }
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn into_program(self) -> Program {
+        self.program
+    }
+
+    pub fn push_input(&mut self, value: i128) {
+        self.program.push_input(value);
+    }
+
+    // how many times an instruction had to be decoded from memory
+    pub fn decodes(&self) -> u64 {
+        self.decodes
+    }
+
+    fn op_at(&mut self, address: usize) -> Result<Op, IntcodeError> {
+        if let Some(Some(op)) = self.ops.get(address) {
+            return Ok(*op);
+        }
+        let op = Op::new(&self.program, &self.program.decode_at(address)?);
+        self.decodes += 1;
+        if address >= self.ops.len() && address < self.program.image().len() {
+            self.ops.resize(self.program.image().len(), None);
+        }
+        if let Some(slot) = self.ops.get_mut(address) {
+            *slot = Some(op);
+        }
+        Ok(op)
+    }
+
+    // drops any cached op with a cell at address
+    fn invalidate(&mut self, address: usize) {
+        let first = address.saturating_sub(MAX_WIDTH - 1);
+        let last = address.saturating_add(1).min(self.ops.len());
+        for start in first..last {
+            if let Some(op) = self.ops[start] {
+                if start + op.width() > address {
+                    self.ops[start] = None;
+                }
+            }
+        }
+    }
+
+    fn read(&self, operand: Operand) -> Result<i128, IntcodeError> {
+        match operand {
+            Operand::Imm(value) => Ok(value),
+            operand => Ok(self.program.memory.get(self.address(operand)?)),
+        }
+    }
+
+    // where a written operand goes; an immediate is used as a position,
+    // as Program::step does
+    fn address(&self, operand: Operand) -> Result<usize, IntcodeError> {
+        let prog = &self.program;
+        match operand {
+            Operand::Pos(address) => Ok(address),
+            Operand::Rel(offset) => prog.relative_addr(offset),
+            Operand::Imm(value) | Operand::Bad(value) => prog.to_address(value),
+        }
+    }
+
+    fn write(&mut self, address: usize, value: i128) {
+        self.program.memory.set(address, value);
+        self.invalidate(address);
+    }
+
+    // Same as Program::step, including errors and the state they leave.
+    pub fn step(&mut self) -> Result<Option<StopReason>, IntcodeError> {
+        self.program.done = false;
+        self.program.input_needed = false;
+        let inst_ptr = self.program.inst_ptr;
+        let op = self.op_at(inst_ptr)?;
+        let mut next = inst_ptr + op.width();
+        let mut reason = None;
+        match op {
+            Op::Add(a, b, c) | Op::Mul(a, b, c) | Op::Lt(a, b, c) | Op::Eq(a, b, c) => {
+                let (a, b) = (self.read(a)?, self.read(b)?);
+                let address = self.address(c)?;
+                let value = match op {
+                    Op::Add(..) => self.program.checked(a.checked_add(b))?,
+                    Op::Mul(..) => self.program.checked(a.checked_mul(b))?,
+                    Op::Lt(..) => (a < b) as i128,
+                    _ => (a == b) as i128,
+                };
+                self.write(address, value);
+            }
+            Op::In(a) => {
+                if self.program.input_ptr >= self.program.input.len() {
+                    self.program.input_needed = true;
+                    return Ok(Some(StopReason::NeedsInput));
+                }
+                let address = self.address(a)?;
+                let value = self.program.input[self.program.input_ptr];
+                self.write(address, value);
+                self.program.input_ptr += 1;
+            }
+            Op::Out(a) => reason = Some(StopReason::Output(self.read(a)?)),
+            Op::Jnz(a, b) | Op::Jz(a, b) => {
+                let taken = (self.read(a)? != 0) == matches!(op, Op::Jnz(..));
+                let target = self.read(b)?;
+                if taken {
+                    next = self.program.to_address(target)?;
+                }
+            }
+            Op::Arb(a) => {
+                let offset = self.read(a)?;
+                self.program.relative_base = self.program.moved_relative_base(offset)?;
+            }
+            Op::Hlt => {
+                self.program.done = true;
+                return Ok(Some(StopReason::Halted));
+            }
+        }
+        self.program.inst_ptr = next;
+        Ok(reason)
+    }
+
+    pub fn run_until_event(&mut self) -> Result<StopReason, IntcodeError> {
+        loop {
+            if let Some(reason) = self.step()? {
+                return Ok(reason);
+            }
+        }
+    }
+
+    // Runs until the program halts or needs input, returning the outputs
+    // along the way.
+    pub fn run_until_blocked(&mut self) -> Result<Vec<i128>, IntcodeError> {
+        let mut outputs = vec![];
+        loop {
+            match self.run_until_event()? {
+                StopReason::Output(value) => outputs.push(value),
+                _ => return Ok(outputs),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::parse_values;
+    use std::time::Instant;
+
+    const BOOST: &str = include_str!("../../data/input_09.txt");
+    const DIAGNOSTIC: &str = include_str!("../../data/input_05.txt");
+
+    fn assert_same(cached: &Cached, prog: &Program) {
+        let flags = |prog: &Program| (prog.is_done(), prog.needs_input());
+        assert!(cached.program().same_state(prog));
+        assert_eq!(flags(cached.program()), flags(prog));
+    }
+
+    // runs both engines side by side, comparing every step
+    fn compare(code: &str, input: &str) -> Vec<i128> {
+        let mut prog = Program::new(code, input).unwrap();
+        let mut cached = Cached::new(prog.clone());
+        let mut outputs = vec![];
+        loop {
+            let expected = prog.step();
+            assert_eq!(cached.step(), expected);
+            assert_same(&cached, &prog);
+            match expected {
+                Ok(Some(StopReason::Output(value))) => outputs.push(value),
+                Ok(None) => {}
+                _ => return outputs,
+            }
+        }
+    }
+
+    #[test]
+    fn same_as_step() {
+        let quine = "109,1,204,-1,1001,100,1,100,1008,100,16,101,1006,101,0,99";
+        assert_eq!(compare(quine, ""), parse_values(quine).unwrap());
+        assert_eq!(compare(BOOST, "1"), vec![4_261_108_180]);
+        let outputs = compare(DIAGNOSTIC, "1");
+        assert!(outputs[..outputs.len() - 1].iter().all(|v| *v == 0));
+        assert_eq!(compare(DIAGNOSTIC, "5").len(), 1);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        // OUT #1, then overwrite it with a HLT and jump back to it
+        let code = "104, 1, 1101, 0, 99, 0, 1105, 1, 0";
+        assert_eq!(compare(code, ""), vec![1]);
+        let mut cached = Cached::new(Program::new(code, "").unwrap());
+        assert_eq!(cached.run_until_blocked().unwrap(), vec![1]);
+        assert!(cached.program().is_done());
+        assert_eq!(cached.decodes(), 4);
+    }
+
+    #[test]
+    fn errors_and_input() {
+        for (code, input) in [
+            ("1, -1, 0, 0, 99", ""),
+            ("109, -1, 99", ""),
+            ("1105, 1, -3", ""),
+            ("42", ""),
+            ("3, 5, 3, 5, 99, 0", "7"),
+            ("203, -1, 99", "1"),
+            (
+                "1101, 170141183460469231731687303715884105727, 1, 0, 99",
+                "",
+            ),
+        ]
+        .iter()
+        {
+            compare(code, input);
+        }
+        let mut cached = Cached::new(Program::new("3, 5, 4, 5, 99, 0", "").unwrap());
+        assert_eq!(cached.run_until_event().unwrap(), StopReason::NeedsInput);
+        cached.push_input(9);
+        assert_eq!(cached.run_until_blocked().unwrap(), vec![9]);
+    }
+
+    #[test]
+    fn write_to_last_address() {
+        let code = format!("1101, 1, 1, {}, 99", usize::MAX);
+        assert!(compare(&code, "").is_empty());
+        let mut cached = Cached::new(Program::new(&code, "").unwrap());
+        assert_eq!(cached.run_until_event().unwrap(), StopReason::Halted);
+        assert_eq!(cached.program().peek(usize::MAX), 2);
+    }
+
+    #[test]
+    fn error_after_needing_input() {
+        // the IN fails once there is input, and neither engine still says
+        // it needs input afterwards
+        let mut prog = Program::new("3, -1, 99", "").unwrap();
+        let mut cached = Cached::new(prog.clone());
+        assert_eq!(prog.step(), Ok(Some(StopReason::NeedsInput)));
+        assert_eq!(cached.step(), Ok(Some(StopReason::NeedsInput)));
+        assert_same(&cached, &prog);
+        prog.push_input(1);
+        cached.push_input(1);
+        let expected = prog.step();
+        assert!(expected.is_err());
+        assert_eq!(cached.step(), expected);
+        assert_same(&cached, &prog);
+        assert!(!cached.program().needs_input());
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_boost() {
+        let start = Instant::now();
+        let mut prog = Program::new(BOOST, "2").unwrap();
+        prog.run_until_blocked().unwrap();
+        let stepped = start.elapsed();
+
+        let start = Instant::now();
+        let mut cached = Cached::new(Program::new(BOOST, "2").unwrap());
+        let outputs = cached.run_until_blocked().unwrap();
+        let fast = start.elapsed();
+
+        assert_eq!(outputs, prog.drain_outputs());
+        println!(
+            "BOOST part 2: step {:?}, cached {:?} ({:.1}x, {} decodes)",
+            stepped,
+            fast,
+            stepped.as_secs_f64() / fast.as_secs_f64(),
+            cached.decodes()
+        );
+    }
+}
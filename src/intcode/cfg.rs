@@ -0,0 +1,235 @@
+use super::disasm::{disassemble, Kind, Listing};
+use super::{Instruction, Mode, Opcode};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+// Control flow graph over the code the disassembler finds. Blocks end at a
+// JNZ, JZ or HLT, or just before another block's first instruction (a jump
+// target, or a return address the program pushes). Jumps whose target
+// comes from memory can't be followed statically and are marked Indirect.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    Fall(usize),
+    Jump(usize),
+    Indirect,
+    Halt,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub start: usize,
+    pub end: usize,
+    pub instructions: Vec<(usize, Instruction)>,
+    pub exits: Vec<Exit>,
+}
+
+pub struct Cfg {
+    pub blocks: BTreeMap<usize, Block>,
+    listing: Listing,
+}
+
+fn exits(address: usize, inst: &Instruction) -> Vec<Exit> {
+    let next = address + inst.width();
+    let params = inst.params();
+    match inst.opcode {
+        Opcode::Hlt => vec![Exit::Halt],
+        Opcode::Jnz | Opcode::Jz => {
+            let (cond, target) = (params[0], params[1]);
+            let taken = (inst.opcode == Opcode::Jnz) == (cond.value != 0);
+            let always = cond.mode == Mode::Immediate && taken;
+            let never = cond.mode == Mode::Immediate && !taken;
+            let mut exits = vec![];
+            if !always {
+                exits.push(Exit::Fall(next));
+            }
+            if !never {
+                if target.mode != Mode::Immediate {
+                    exits.push(Exit::Indirect);
+                } else if target.value >= 0 {
+                    exits.push(Exit::Jump(target.value as usize));
+                }
+            }
+            exits
+        }
+        _ => vec![Exit::Fall(next)],
+    }
+}
+
+fn ends_block(inst: &Instruction) -> bool {
+    inst.opcode.is_jump() || inst.opcode == Opcode::Hlt
+}
+
+pub fn build(image: &[i128]) -> Cfg {
+    let listing = disassemble(image);
+    let code: BTreeMap<usize, Instruction> = listing
+        .lines
+        .iter()
+        .filter_map(|line| match line.kind {
+            Kind::Code(inst) => Some((line.address, inst)),
+            Kind::Data(_) => None,
+        })
+        .collect();
+
+    let mut leaders: BTreeSet<usize> = listing.labels.keys().copied().collect();
+    leaders.insert(0);
+    for (address, inst) in code.iter() {
+        if ends_block(inst) {
+            leaders.insert(address + inst.width());
+        }
+        for exit in exits(*address, inst) {
+            if let Exit::Jump(target) = exit {
+                leaders.insert(target);
+            }
+        }
+    }
+
+    let mut blocks = BTreeMap::new();
+    let mut current: Option<Block> = None;
+    for (address, inst) in code.iter() {
+        let mut block = match current.take() {
+            Some(block) if block.end == *address && !leaders.contains(address) => block,
+            other => {
+                if let Some(block) = other {
+                    blocks.insert(block.start, block);
+                }
+                Block {
+                    start: *address,
+                    end: *address,
+                    instructions: vec![],
+                    exits: vec![],
+                }
+            }
+        };
+        block.instructions.push((*address, *inst));
+        block.end = address + inst.width();
+        block.exits = exits(*address, inst);
+        current = Some(block);
+    }
+    if let Some(block) = current {
+        blocks.insert(block.start, block);
+    }
+
+    Cfg { blocks, listing }
+}
+
+impl Cfg {
+    pub fn block_containing(&self, address: usize) -> Option<&Block> {
+        self.blocks
+            .range(..=address)
+            .next_back()
+            .map(|(_, block)| block)
+            .filter(|block| address < block.end)
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph intcode {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        let mut indirect = false;
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            if let Some(name) = self.listing.label(block.start) {
+                label.push_str(&format!("{}:\\l", name));
+            }
+            for (address, inst) in block.instructions.iter() {
+                let text = self.listing.format_instruction(*address, inst);
+                label.push_str(&format!("{:>6}: {}\\l", address, text));
+            }
+            let style = if block.exits.contains(&Exit::Halt) {
+                ", peripheries=2"
+            } else {
+                ""
+            };
+            writeln!(dot, "    b{} [label=\"{}\"{}];", block.start, label, style).unwrap();
+            for exit in block.exits.iter() {
+                match exit {
+                    Exit::Fall(to) if self.blocks.contains_key(to) => {
+                        writeln!(dot, "    b{} -> b{};", block.start, to).unwrap()
+                    }
+                    Exit::Jump(to) if self.blocks.contains_key(to) => {
+                        writeln!(dot, "    b{} -> b{} [label=\"jump\"];", block.start, to).unwrap()
+                    }
+                    Exit::Indirect => {
+                        indirect = true;
+                        writeln!(dot, "    b{} -> indirect [style=dashed];", block.start).unwrap()
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if indirect {
+            writeln!(dot, "    indirect [shape=diamond, label=\"?\"];").unwrap();
+        }
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::parse_values;
+
+    #[test]
+    fn blocks_and_exits() {
+        // input, jump to 9 if zero, output, halt
+        let image = parse_values("3, 12, 1006, 12, 9, 4, 12, 99, 7, 104, 0, 99, 0").unwrap();
+        let cfg = build(&image);
+        let summary: Vec<(usize, usize, Vec<Exit>)> = cfg
+            .blocks
+            .values()
+            .map(|block| (block.start, block.end, block.exits.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, 5, vec![Exit::Fall(5), Exit::Jump(9)]),
+                (5, 8, vec![Exit::Halt]),
+                (9, 12, vec![Exit::Halt]),
+            ]
+        );
+        assert_eq!(cfg.blocks[&0].instructions.len(), 2);
+        assert_eq!(cfg.block_containing(6).unwrap().start, 5);
+        assert!(cfg.block_containing(8).is_none());
+    }
+
+    #[test]
+    fn loops_and_indirect_jumps() {
+        // push return address 9, call the subroutine at 12 which returns
+        // through rb+0
+        let image =
+            parse_values("109, 20, 21101, 0, 9, 0, 1105, 1, 12, 99, 0, 0, 2106, 0, 0").unwrap();
+        let cfg = build(&image);
+        let starts: Vec<usize> = cfg.blocks.keys().copied().collect();
+        assert_eq!(starts, vec![0, 9, 12]);
+        assert_eq!(cfg.blocks[&0].exits, vec![Exit::Jump(12)]);
+        assert_eq!(cfg.blocks[&12].exits, vec![Exit::Indirect]);
+
+        // the quine loops back to its start
+        let image =
+            parse_values("109,1,204,-1,1001,100,1,100,1008,100,16,101,1006,101,0,99").unwrap();
+        let cfg = build(&image);
+        assert_eq!(cfg.blocks[&0].exits, vec![Exit::Fall(15), Exit::Jump(0)]);
+    }
+
+    #[test]
+    fn dot() {
+        let image =
+            parse_values("109, 20, 21101, 0, 9, 0, 1105, 1, 12, 99, 0, 0, 2106, 0, 0").unwrap();
+        assert_eq!(
+            build(&image).to_dot(),
+            "digraph intcode {\n\
+             \x20   node [shape=box, fontname=\"monospace\"];\n\
+             \x20   b0 [label=\"     0: ARB #20\\l     2: ADD #0, #L9, rb+0\\l     6: JNZ #1, #L12\\l\"];\n\
+             \x20   b0 -> b12 [label=\"jump\"];\n\
+             \x20   b9 [label=\"L9:\\l     9: HLT\\l\", peripheries=2];\n\
+             \x20   b12 [label=\"L12:\\l    12: JZ #0, rb+0\\l\"];\n\
+             \x20   b12 -> indirect [style=dashed];\n\
+             \x20   indirect [shape=diamond, label=\"?\"];\n\
+             }\n"
+        );
+    }
+}
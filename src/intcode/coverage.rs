@@ -0,0 +1,264 @@
+use super::{Executed, Instruction, Observer, Program};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+const DATA_PER_LINE: usize = 8;
+
+// Which cells a run touched, and how:
+//
+//     let mut coverage = Coverage::default();
+//     prog.run_observed(&mut coverage)?;
+//     print!("{}", coverage.listing(&prog));
+//
+// A cell counts as executed if it was part of an executed instruction
+// (opcode or operand), and as read or written if an instruction used it as
+// data. Self-modifying code shows up as cells that are both.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Usage {
+    pub executed: bool,
+    pub read: bool,
+    pub written: bool,
+}
+
+impl Usage {
+    pub fn is_code(&self) -> bool {
+        self.executed
+    }
+
+    pub fn is_data(&self) -> bool {
+        !self.executed && (self.read || self.written)
+    }
+
+    pub fn is_untouched(&self) -> bool {
+        !(self.executed || self.read || self.written)
+    }
+
+    fn merge(self, other: Usage) -> Usage {
+        Usage {
+            executed: self.executed || other.executed,
+            read: self.read || other.read,
+            written: self.written || other.written,
+        }
+    }
+
+    // "xrw" with a dash for each use that didn't happen
+    pub fn flags(&self) -> String {
+        let flag = |set, c| if set { c } else { '-' };
+        [
+            flag(self.executed, 'x'),
+            flag(self.read, 'r'),
+            flag(self.written, 'w'),
+        ]
+        .iter()
+        .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    cells: BTreeMap<usize, Usage>,
+    // last instruction executed at each start address
+    starts: BTreeMap<usize, Instruction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub executed: usize,
+    pub data: usize,
+    pub untouched: usize,
+}
+
+impl Coverage {
+    pub fn usage(&self, address: usize) -> Usage {
+        self.cells.get(&address).copied().unwrap_or_default()
+    }
+
+    pub fn instruction_starts(&self) -> impl Iterator<Item = usize> + '_ {
+        self.starts.keys().copied()
+    }
+
+    // counts over the program image plus anything touched beyond it
+    pub fn summary(&self, prog: &Program) -> Summary {
+        let mut summary = Summary {
+            executed: 0,
+            data: 0,
+            untouched: 0,
+        };
+        for address in self.addresses(prog) {
+            let usage = self.usage(address);
+            if usage.is_code() {
+                summary.executed += 1;
+            } else if usage.is_data() {
+                summary.data += 1;
+            } else {
+                summary.untouched += 1;
+            }
+        }
+        summary
+    }
+
+    fn addresses(&self, prog: &Program) -> BTreeSet<usize> {
+        let mut addresses: BTreeSet<usize> = (0..prog.image().len()).collect();
+        addresses.extend(self.cells.keys());
+        addresses
+    }
+
+    fn mark(&mut self, address: usize, usage: Usage) {
+        let cell = self.cells.entry(address).or_default();
+        *cell = cell.merge(usage);
+    }
+
+    // One line per executed instruction, with the cells in between grouped
+    // into .data lines of matching usage. Values are the current contents
+    // of memory, instructions are as they were last executed.
+    pub fn listing(&self, prog: &Program) -> String {
+        let addresses: Vec<usize> = self.addresses(prog).into_iter().collect();
+        let mut text = String::new();
+        let mut i = 0;
+        while i < addresses.len() {
+            let address = addresses[i];
+            if let Some(inst) = self.starts.get(&address) {
+                let end = address + inst.width();
+                let usage = (address..end).fold(Usage::default(), |u, a| u.merge(self.usage(a)));
+                writeln!(text, "{:>6} {}  {}", address, usage.flags(), inst).unwrap();
+                while i < addresses.len()
+                    && addresses[i] < end
+                    && (addresses[i] == address || !self.starts.contains_key(&addresses[i]))
+                {
+                    i += 1;
+                }
+                continue;
+            }
+            let usage = self.usage(address);
+            let mut values = vec![prog.peek(address).to_string()];
+            i += 1;
+            while i < addresses.len()
+                && values.len() < DATA_PER_LINE
+                && addresses[i] == addresses[i - 1] + 1
+                && !self.starts.contains_key(&addresses[i])
+                && self.usage(addresses[i]) == usage
+            {
+                values.push(prog.peek(addresses[i]).to_string());
+                i += 1;
+            }
+            writeln!(
+                text,
+                "{:>6} {}  .data {}",
+                address,
+                usage.flags(),
+                values.join(", ")
+            )
+            .unwrap();
+        }
+        let summary = self.summary(prog);
+        writeln!(
+            text,
+            "; {} executed, {} data, {} untouched",
+            summary.executed, summary.data, summary.untouched
+        )
+        .unwrap();
+        text
+    }
+}
+
+impl Observer for Coverage {
+    fn observe(&mut self, _prog: &Program, executed: &Executed) {
+        let address = executed.address;
+        let width = executed.instruction.width();
+        for cell in address..address + width {
+            self.mark(
+                cell,
+                Usage {
+                    executed: true,
+                    ..Usage::default()
+                },
+            );
+        }
+        self.starts.insert(address, executed.instruction);
+        for cell in executed.reads() {
+            self.mark(
+                cell,
+                Usage {
+                    read: true,
+                    ..Usage::default()
+                },
+            );
+        }
+        if let Some(write) = executed.write {
+            self.mark(
+                write.address,
+                Usage {
+                    written: true,
+                    ..Usage::default()
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::testing::run_observed_to_end;
+    use super::super::StopReason;
+
+    fn cover(code: &str, input: &str) -> (Program, Coverage) {
+        let mut prog = Program::new(code, input).unwrap();
+        let mut coverage = Coverage::default();
+        assert_eq!(
+            run_observed_to_end(&mut prog, &mut coverage),
+            StopReason::Halted
+        );
+        (prog, coverage)
+    }
+
+    #[test]
+    fn code_data_and_untouched() {
+        // input, jump to 9 if zero, output, halt; 8 and 9..11 never run
+        let (prog, coverage) = cover("3, 12, 1006, 12, 9, 4, 12, 99, 7, 104, 0, 99, 0", "5");
+        assert!(coverage.usage(0).is_code());
+        assert!(coverage.usage(4).is_code());
+        assert!(coverage.usage(8).is_untouched());
+        assert!(coverage.usage(9).is_untouched());
+        assert!(coverage.usage(12).is_data());
+        assert_eq!(coverage.usage(12).flags(), "-rw");
+        assert_eq!(
+            coverage.instruction_starts().collect::<Vec<_>>(),
+            vec![0, 2, 5, 7]
+        );
+        assert_eq!(
+            coverage.summary(&prog),
+            Summary {
+                executed: 8,
+                data: 1,
+                untouched: 4
+            }
+        );
+        assert_eq!(
+            coverage.listing(&prog),
+            "     0 x--  IN [12]\n\
+             \x20    2 x--  JZ [12], #9\n\
+             \x20    5 x--  OUT [12]\n\
+             \x20    7 x--  HLT\n\
+             \x20    8 ---  .data 7, 104, 0, 99\n\
+             \x20   12 -rw  .data 5\n\
+             ; 8 executed, 1 data, 4 untouched\n"
+        );
+    }
+
+    #[test]
+    fn self_modifying_and_beyond_image() {
+        // stores 7 far away, then writes a HLT over the OUT at 8
+        let (prog, coverage) = cover("1101, 3, 4, 5000, 1101, 90, 9, 8, 104, 1, 99", "");
+        assert_eq!(coverage.usage(8).flags(), "x-w");
+        assert_eq!(coverage.usage(5000).flags(), "--w");
+        let listing = coverage.listing(&prog);
+        assert!(listing.contains("     4 x--  ADD #90, #9, [8]\n"));
+        assert!(listing.contains("     8 x-w  HLT\n"));
+        assert!(listing.contains("     9 ---  .data 1, 99\n"));
+        assert!(listing.contains("  5000 --w  .data 7\n"));
+    }
+}
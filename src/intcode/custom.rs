@@ -0,0 +1,430 @@
+use super::{to_word, IntcodeError, Mode, Opcode, Param, Program, StopReason, Word, OPCODES};
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Extra opcodes for experimental variants of the machine. Each one is
+// registered with its parameters and a handler, and run_until_event runs
+// them alongside the built-in instructions:
+//
+//     let mut ops = Registry::new();
+//     ops.register(20, "PRN", &[Kind::Read], |call| {
+//         println!("{}", call.arg(0));
+//         Ok(Effect::Next)
+//     })?;
+//     ops.run_until_event(&mut prog)?;
+//
+// Custom opcodes are decoded like the built-in ones (modes in the hundreds
+// and up), but don't go through preview, so observers don't see them.
+
+// How a parameter is handed to the handler: the value it reads (any mode),
+// or the address it refers to (position or relative mode) for writing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Read,
+    Write,
+}
+
+// What happens after a handler returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    // go on to the next instruction
+    Next,
+    Jump(usize),
+    Output(i128),
+    Halt,
+    // stay on this instruction and ask for input; the handler shouldn't
+    // have changed anything
+    NeedsInput,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryError {
+    // opcodes are the last two digits of an instruction
+    OutOfRange(i128),
+    Builtin { code: i128, opcode: Opcode },
+    BuiltinMnemonic { mnemonic: String, opcode: Opcode },
+    Duplicate { code: i128, mnemonic: String },
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistryError::OutOfRange(code) => write!(f, "opcode {} is not in 1..=99", code),
+            RegistryError::Builtin { code, opcode } => write!(
+                f,
+                "opcode {} is already the built-in {}",
+                code,
+                opcode.mnemonic()
+            ),
+            RegistryError::BuiltinMnemonic { mnemonic, opcode } => write!(
+                f,
+                "mnemonic {} is already the built-in opcode {}",
+                mnemonic,
+                opcode.code()
+            ),
+            RegistryError::Duplicate { code, mnemonic } => {
+                write!(f, "opcode {} is already registered as {}", code, mnemonic)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+// The machine as a handler sees it.
+pub struct Call<'a, W: Word = i128> {
+    prog: &'a mut Program<W>,
+    params: &'a [Kind],
+    args: Vec<i128>,
+}
+
+impl<'a, W: Word> Call<'a, W> {
+    // the value of a Read param, or the address of a Write param
+    pub fn arg(&self, index: usize) -> i128 {
+        self.args[index]
+    }
+
+    pub fn args(&self) -> &[i128] {
+        &self.args
+    }
+
+    pub fn program(&self) -> &Program<W> {
+        self.prog
+    }
+
+    // Writes value to the address of a Write param. Values that don't fit
+    // the word type are an Overflow error, and an index that isn't a Write
+    // param is NotAWriteParam.
+    pub fn write(&mut self, index: usize, value: i128) -> Result<(), IntcodeError> {
+        if self.params.get(index) != Some(&Kind::Write) {
+            return Err(IntcodeError::NotAWriteParam {
+                inst_ptr: self.prog.inst_ptr,
+                instruction: self.prog.instruction(),
+                operand_index: index + 1,
+            });
+        }
+        let value = self.prog.checked(Some(value))?;
+        self.prog
+            .memory
+            .set(self.args[index] as usize, to_word(value));
+        Ok(())
+    }
+
+    // the next input, or None if there is none left
+    pub fn read_input(&mut self) -> Option<i128> {
+        let input = self.prog.input.get(self.prog.input_ptr).copied()?;
+        self.prog.input_ptr += 1;
+        Some(input)
+    }
+}
+
+type Handler<W> = Box<dyn FnMut(&mut Call<W>) -> Result<Effect, IntcodeError>>;
+
+struct Custom<W: Word> {
+    mnemonic: String,
+    params: Vec<Kind>,
+    handler: Handler<W>,
+}
+
+#[derive(Default)]
+pub struct Registry<W: Word = i128> {
+    ops: BTreeMap<i128, Custom<W>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+}
+
+impl<W: Word> Registry<W> {
+    pub fn register<F>(
+        &mut self,
+        code: i128,
+        mnemonic: &str,
+        params: &[Kind],
+        handler: F,
+    ) -> Result<(), RegistryError>
+    where
+        F: FnMut(&mut Call<W>) -> Result<Effect, IntcodeError> + 'static,
+    {
+        if !(1..=99).contains(&code) {
+            return Err(RegistryError::OutOfRange(code));
+        }
+        if let Some(opcode) = Opcode::from_code(code) {
+            return Err(RegistryError::Builtin { code, opcode });
+        }
+        if let Some(opcode) = Opcode::from_mnemonic(mnemonic) {
+            return Err(RegistryError::BuiltinMnemonic {
+                mnemonic: mnemonic.to_string(),
+                opcode,
+            });
+        }
+        if let Some(custom) = self.ops.get(&code) {
+            return Err(RegistryError::Duplicate {
+                code,
+                mnemonic: custom.mnemonic.clone(),
+            });
+        }
+        self.ops.insert(
+            code,
+            Custom {
+                mnemonic: mnemonic.to_string(),
+                params: params.to_vec(),
+                handler: Box::new(handler),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn unregister(&mut self, code: i128) -> bool {
+        self.ops.remove(&code).is_some()
+    }
+
+    pub fn mnemonic(&self, code: i128) -> Option<&str> {
+        self.ops.get(&code).map(|custom| custom.mnemonic.as_str())
+    }
+
+    pub fn arity(&self, code: i128) -> Option<usize> {
+        self.ops.get(&code).map(|custom| custom.params.len())
+    }
+
+    // (code, mnemonic) of every custom opcode, in code order
+    pub fn opcodes(&self) -> impl Iterator<Item = (i128, &str)> {
+        self.ops
+            .iter()
+            .map(|(code, custom)| (*code, custom.mnemonic.as_str()))
+    }
+
+    // the resolved args of the custom instruction at inst_ptr
+    fn args(prog: &Program<W>, params: &[Kind]) -> Result<Vec<i128>, IntcodeError> {
+        let instruction = prog.instruction();
+        let mut modes = instruction / 100;
+        let mut args = vec![];
+        for (i, kind) in params.iter().enumerate() {
+            let mode = match Mode::from_code(modes % 10) {
+                Some(mode) => mode,
+                None => {
+                    return Err(IntcodeError::InvalidMode {
+                        inst_ptr: prog.inst_ptr,
+                        instruction,
+                        operand_index: i + 1,
+                        mode: modes % 10,
+                    })
+                }
+            };
+            let param = Param {
+                mode,
+                value: prog.peek(prog.inst_ptr + i + 1),
+            };
+            args.push(match kind {
+                Kind::Read => prog.param_value(param)?.0,
+                Kind::Write => prog.param_addr(param)? as i128,
+            });
+            modes /= 10;
+        }
+        Ok(args)
+    }
+
+    // Program::step, with the custom opcodes added.
+    pub fn step(&mut self, prog: &mut Program<W>) -> Result<Option<StopReason>, IntcodeError> {
+        let custom = match self.ops.get_mut(&(prog.instruction() % 100)) {
+            Some(custom) => custom,
+            None => return prog.step(),
+        };
+        prog.done = false;
+        prog.input_needed = false;
+        let args = Registry::args(prog, &custom.params)?;
+        let next = prog.inst_ptr + custom.params.len() + 1;
+        let mut call = Call {
+            prog,
+            params: &custom.params,
+            args,
+        };
+        let effect = (custom.handler)(&mut call)?;
+        match effect {
+            Effect::Next | Effect::Output(_) => prog.inst_ptr = next,
+            Effect::Jump(target) => prog.inst_ptr = target,
+            Effect::Halt => prog.done = true,
+            Effect::NeedsInput => prog.input_needed = true,
+        }
+        Ok(match effect {
+            Effect::Next | Effect::Jump(_) => None,
+            Effect::Output(value) => Some(StopReason::Output(value)),
+            Effect::Halt => Some(StopReason::Halted),
+            Effect::NeedsInput => Some(StopReason::NeedsInput),
+        })
+    }
+
+    pub fn run_until_event(&mut self, prog: &mut Program<W>) -> Result<StopReason, IntcodeError> {
+        loop {
+            if let Some(reason) = self.step(prog)? {
+                return Ok(reason);
+            }
+        }
+    }
+
+    // Runs until the program halts or needs input, returning the outputs
+    // along the way.
+    pub fn run_until_blocked(&mut self, prog: &mut Program<W>) -> Result<Vec<i128>, IntcodeError> {
+        let mut outputs = vec![];
+        loop {
+            match self.run_until_event(prog)? {
+                StopReason::Output(value) => outputs.push(value),
+                _ => return Ok(outputs),
+            }
+        }
+    }
+}
+
+impl<W: Word> fmt::Debug for Registry<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.opcodes()).finish()
+    }
+}
+
+// every code a custom opcode could use
+pub fn free_codes() -> impl Iterator<Item = i128> {
+    (1..100).filter(|code| OPCODES.iter().all(|op| op.code() != *code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn collisions() {
+        let mut ops = Registry::new();
+        let next = |_: &mut Call| Ok(Effect::Next);
+        assert_eq!(
+            ops.register(2, "DBL", &[Kind::Write], next),
+            Err(RegistryError::Builtin {
+                code: 2,
+                opcode: Opcode::Mul
+            })
+        );
+        assert_eq!(
+            ops.register(2, "DBL", &[Kind::Write], next)
+                .unwrap_err()
+                .to_string(),
+            "opcode 2 is already the built-in MUL"
+        );
+        assert_eq!(
+            ops.register(20, "out", &[Kind::Read], next),
+            Err(RegistryError::BuiltinMnemonic {
+                mnemonic: "out".to_string(),
+                opcode: Opcode::Out
+            })
+        );
+        assert_eq!(
+            ops.register(100, "BIG", &[], next),
+            Err(RegistryError::OutOfRange(100))
+        );
+        ops.register(20, "PRN", &[Kind::Read], next).unwrap();
+        assert_eq!(
+            ops.register(20, "NOP", &[], next),
+            Err(RegistryError::Duplicate {
+                code: 20,
+                mnemonic: "PRN".to_string()
+            })
+        );
+        assert_eq!(ops.mnemonic(20), Some("PRN"));
+        assert_eq!(ops.arity(20), Some(1));
+        assert_eq!(free_codes().count(), 89);
+    }
+
+    #[test]
+    fn custom_opcodes() {
+        let printed = Rc::new(RefCell::new(vec![]));
+        let log = printed.clone();
+        let mut ops = Registry::new();
+        // PRN a: prints a
+        ops.register(20, "PRN", &[Kind::Read], move |call| {
+            log.borrow_mut().push(call.arg(0));
+            Ok(Effect::Next)
+        })
+        .unwrap();
+        // SQR a, b: b = a * a, then outputs it
+        ops.register(21, "SQR", &[Kind::Read, Kind::Write], |call| {
+            let square = call.arg(0) * call.arg(0);
+            call.write(1, square)?;
+            Ok(Effect::Output(square))
+        })
+        .unwrap();
+        // GET a: a = input
+        ops.register(22, "GET", &[Kind::Write], |call| match call.read_input() {
+            Some(value) => {
+                call.write(0, value)?;
+                Ok(Effect::Next)
+            }
+            None => Ok(Effect::NeedsInput),
+        })
+        .unwrap();
+
+        let mut prog = Program::new("22, 11, 20, 11, 21, 11, 12, 20, 12, 99, 0, 0, 0", "").unwrap();
+        assert_eq!(
+            ops.run_until_event(&mut prog).unwrap(),
+            StopReason::NeedsInput
+        );
+        assert_eq!(prog.inst_ptr(), 0);
+        prog.push_input(7);
+        assert_eq!(ops.run_until_blocked(&mut prog).unwrap(), vec![49]);
+        assert!(prog.is_done());
+        assert_eq!(*printed.borrow(), vec![7, 49]);
+        assert_eq!(prog.peek(12), 49);
+    }
+
+    #[test]
+    fn errors() {
+        let mut ops = Registry::<i64>::default();
+        ops.register(20, "BIG", &[Kind::Write], |call| {
+            call.write(0, i128::from(i64::MAX) + 1)?;
+            Ok(Effect::Next)
+        })
+        .unwrap();
+        let mut prog = Program::<i64>::parse("20, 3, 99, 0", "").unwrap();
+        assert_eq!(
+            ops.step(&mut prog),
+            Err(IntcodeError::Overflow {
+                inst_ptr: 0,
+                instruction: 20
+            })
+        );
+        let mut prog = Program::<i64>::parse("320, 3, 99, 0", "").unwrap();
+        assert!(matches!(
+            ops.step(&mut prog),
+            Err(IntcodeError::InvalidMode { mode: 3, .. })
+        ));
+        // writing through a Read param, or one the opcode doesn't have
+        ops.register(21, "BAD", &[Kind::Read], |call| {
+            call.write(call.arg(0) as usize, 0)?;
+            Ok(Effect::Next)
+        })
+        .unwrap();
+        let mut prog = Program::<i64>::parse("1121, 0, 99", "").unwrap();
+        assert_eq!(
+            ops.step(&mut prog),
+            Err(IntcodeError::NotAWriteParam {
+                inst_ptr: 0,
+                instruction: 1121,
+                operand_index: 1
+            })
+        );
+        let mut prog = Program::<i64>::parse("121, 5, 99", "").unwrap();
+        assert_eq!(
+            ops.step(&mut prog).unwrap_err().to_string(),
+            "operand 6 is not a write parameter of instruction 121 at 0"
+        );
+        assert_eq!(prog.peek(5), 0);
+        // unknown opcodes still fail as before
+        let mut prog = Program::<i64>::parse("23, 99", "").unwrap();
+        assert!(matches!(
+            ops.step(&mut prog),
+            Err(IntcodeError::InvalidOpcode { .. })
+        ));
+    }
+}
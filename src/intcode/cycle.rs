@@ -0,0 +1,153 @@
+use super::{IntcodeError, Program, StopReason};
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+// Detects programs that can never make progress again. Between two I/O
+// events the machine is deterministic, so if its whole state (memory,
+// inst_ptr, relative_base, input cursor) ever repeats it will cycle through
+// the same states forever.
+//
+// States are compared by a memory hash that is updated only for the cells
+// each instruction writes, and cycles are found with Brent's algorithm so
+// nothing but the last checkpoint is kept. A matching hash is confirmed by
+// running a copy around the loop once and comparing the states exactly.
+
+fn cell_hash(address: usize, value: i128) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (address, value).hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Key {
+    // xor of cell_hash over the changes since the run started
+    memory: u64,
+    inst_ptr: usize,
+    relative_base: usize,
+    input_ptr: usize,
+}
+
+impl Program {
+    fn key(&self, memory: u64) -> Key {
+        Key {
+            memory,
+            inst_ptr: self.inst_ptr,
+            relative_base: self.relative_base,
+            input_ptr: self.input_ptr,
+        }
+    }
+
+    // Runs a copy length instructions and returns the lowest address it
+    // executed if that brought it back to exactly this state.
+    fn confirm_loop(&self, length: u64) -> Result<Option<usize>, IntcodeError> {
+        let mut copy = self.clone();
+        let mut start = self.inst_ptr;
+        for _ in 0..length {
+            match copy.step_traced()? {
+                (None, Some(executed)) if executed.input.is_none() => {
+                    start = start.min(executed.address);
+                }
+                _ => return Ok(None),
+            }
+        }
+        Ok(if copy.same_state(self) {
+            Some(start)
+        } else {
+            None
+        })
+    }
+
+    // Like run_until_event, but returns Looping instead of running forever
+    // when the program is stuck.
+    pub fn run_detecting_loops(&mut self) -> Result<StopReason, IntcodeError> {
+        let mut memory = 0;
+        let mut checkpoint = self.key(memory);
+        let mut power = 1;
+        let mut length = 0;
+        loop {
+            let executed = match self.step_traced()? {
+                (Some(reason), _) => return Ok(reason),
+                (None, executed) => executed.unwrap(),
+            };
+            if let Some(write) = executed.write {
+                memory ^= cell_hash(write.address, write.old) ^ cell_hash(write.address, write.new);
+            }
+            let key = self.key(memory);
+            if executed.input.is_some() {
+                checkpoint = key;
+                power = 1;
+                length = 0;
+                continue;
+            }
+            length += 1;
+            if key == checkpoint {
+                if let Some(start) = self.confirm_loop(length)? {
+                    return Ok(StopReason::Looping { start, length });
+                }
+            }
+            if length == power {
+                checkpoint = key;
+                power *= 2;
+                length = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jump_to_self() {
+        let mut prog = Program::new("104, 5, 1105, 1, 2", "").unwrap();
+        assert_eq!(prog.run_detecting_loops().unwrap(), StopReason::Output(5));
+        assert_eq!(
+            prog.run_detecting_loops().unwrap(),
+            StopReason::Looping {
+                start: 2,
+                length: 1
+            }
+        );
+    }
+
+    #[test]
+    fn loop_that_changes_memory() {
+        // flips the sign of [10] forever, so the state repeats every other
+        // time round
+        let mut prog = Program::new("102, -1, 10, 10, 1105, 1, 0, 99, 99, 99, 1", "").unwrap();
+        assert_eq!(
+            prog.run_detecting_loops().unwrap(),
+            StopReason::Looping {
+                start: 0,
+                length: 4
+            }
+        );
+    }
+
+    #[test]
+    fn terminating_loops() {
+        // counts [12] up to 500 then halts
+        let mut prog = Program::new(
+            "1001, 12, 1, 12, 1007, 12, 500, 13, 1005, 13, 0, 99, 0, 0",
+            "",
+        )
+        .unwrap();
+        assert_eq!(prog.run_detecting_loops().unwrap(), StopReason::Halted);
+        assert_eq!(prog.peek(12), 500);
+
+        // reads inputs in a loop until they run out
+        let mut prog = Program::new("3, 10, 1105, 1, 0", "1, 2, 3").unwrap();
+        assert_eq!(prog.run_detecting_loops().unwrap(), StopReason::NeedsInput);
+    }
+
+    #[test]
+    fn confirm_rejects_drift() {
+        // the same two instructions forever, but rb keeps moving
+        let prog = Program::new("109, 1, 1105, 1, 0", "").unwrap();
+        assert_eq!(prog.confirm_loop(2).unwrap(), None);
+        let prog = Program::new("1105, 1, 0", "").unwrap();
+        assert_eq!(prog.confirm_loop(1).unwrap(), Some(0));
+    }
+}
@@ -0,0 +1,508 @@
+use super::history::History;
+use super::{IntcodeError, Opcode, Program, StopReason};
+
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+// how many executed instructions the debugger can undo
+const HISTORY_LIMIT: usize = 100_000;
+
+// the most cells or instructions x and dis show at once
+const MAX_COUNT: usize = 1000;
+
+const HELP: &str = "\
+s, step [n]         execute n instructions (default 1)
+c, continue         run until a breakpoint, halt or input is needed
+back [n]            undo n instructions (default 1)
+backto <addr>       undo until the instruction at addr is next again
+who <addr>          show the last instruction that wrote addr
+b, break <addr>     break when execution reaches addr
+b, break <MNEMONIC> break before any instruction with that opcode
+d, delete <addr|MNEMONIC>
+bl, breaks          list breakpoints
+i, info             show registers, pending input and queued output
+x, peek <addr> [n]  show n memory cells starting at addr
+poke <addr> <value> write a memory cell
+in <v>[, v...]      push input values
+out                 show and clear queued output
+dis [addr] [n]      disassemble n instructions (default at inst_ptr)
+q, quit";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pause {
+    Breakpoint(usize),
+    OpcodeBreak(Opcode, usize),
+    Halted,
+    NeedsInput,
+}
+
+pub struct Debugger {
+    pub program: Program,
+    pub history: History,
+    breakpoints: BTreeSet<usize>,
+    opcode_breaks: BTreeSet<Opcode>,
+    quit: bool,
+}
+
+fn parse_number<T: std::str::FromStr>(word: Option<&str>, what: &str) -> Result<T, String> {
+    match word {
+        Some(word) => word.parse().map_err(|_| format!("bad {} {:?}", what, word)),
+        None => Err(format!("missing {}", what)),
+    }
+}
+
+// an optional count for x or dis, up to MAX_COUNT
+fn parse_count(word: Option<&str>, default: usize) -> Result<usize, String> {
+    let count = match word {
+        Some(word) => parse_number(Some(word), "count")?,
+        None => default,
+    };
+    if count > MAX_COUNT {
+        return Err(format!("count {} is more than {}", count, MAX_COUNT));
+    }
+    Ok(count)
+}
+
+impl Debugger {
+    pub fn new(program: Program) -> Debugger {
+        Debugger {
+            program,
+            history: History::with_limit(HISTORY_LIMIT),
+            breakpoints: BTreeSet::new(),
+            opcode_breaks: BTreeSet::new(),
+            quit: false,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, address: usize) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn add_opcode_breakpoint(&mut self, opcode: Opcode) {
+        self.opcode_breaks.insert(opcode);
+    }
+
+    pub fn is_quit(&self) -> bool {
+        self.quit
+    }
+
+    // Executes one instruction. Outputs are queued on the program rather
+    // than returned so they can be looked at later with `out`.
+    pub fn step(&mut self) -> Result<Option<StopReason>, IntcodeError> {
+        let reason = self.program.step_observed(&mut self.history)?;
+        if let Some(StopReason::Output(value)) = reason {
+            self.program.outputs.push_back(value);
+        }
+        Ok(reason)
+    }
+
+    fn breakpoint_here(&self) -> Option<Pause> {
+        let address = self.program.inst_ptr();
+        if self.breakpoints.contains(&address) {
+            return Some(Pause::Breakpoint(address));
+        }
+        match self.program.decode_at(address) {
+            Ok(inst) if self.opcode_breaks.contains(&inst.opcode) => {
+                Some(Pause::OpcodeBreak(inst.opcode, address))
+            }
+            _ => None,
+        }
+    }
+
+    // Always executes at least one instruction, so continuing from a
+    // breakpoint moves past it.
+    pub fn cont(&mut self) -> Result<Pause, IntcodeError> {
+        loop {
+            match self.step()? {
+                Some(StopReason::Halted) => return Ok(Pause::Halted),
+                Some(StopReason::NeedsInput) => return Ok(Pause::NeedsInput),
+                _ => {}
+            }
+            if let Some(pause) = self.breakpoint_here() {
+                return Ok(pause);
+            }
+        }
+    }
+
+    fn current_instruction(&self, address: usize) -> String {
+        match self.program.decode_at(address) {
+            Ok(inst) => format!("{:>6}: {}", address, inst),
+            Err(_) => format!("{:>6}: .data {}", address, self.program.peek(address)),
+        }
+    }
+
+    pub fn status(&self) -> String {
+        let state = if self.program.is_done() {
+            "  (halted)"
+        } else if self.program.needs_input() {
+            "  (needs input)"
+        } else {
+            ""
+        };
+        format!(
+            "ip {}  rb {}  input {:?}  output {:?}{}\n{}",
+            self.program.inst_ptr(),
+            self.program.relative_base(),
+            self.program.pending_input(),
+            self.program.queued_outputs(),
+            state,
+            self.current_instruction(self.program.inst_ptr())
+        )
+    }
+
+    fn describe_pause(&self, pause: Pause) -> String {
+        match pause {
+            Pause::Breakpoint(address) => format!("breakpoint at {}", address),
+            Pause::OpcodeBreak(opcode, address) => {
+                format!("break on {} at {}", opcode.mnemonic(), address)
+            }
+            Pause::Halted => "halted".to_string(),
+            Pause::NeedsInput => "needs input, push some with `in`".to_string(),
+        }
+    }
+
+    fn with_outputs<F>(&mut self, run: F) -> Result<String, String>
+    where
+        F: FnOnce(&mut Debugger) -> Result<String, IntcodeError>,
+    {
+        let before = self.program.outputs.len();
+        let mut text = run(self).map_err(|err| format!("error: {}", err))?;
+        for value in self.program.outputs.iter().skip(before) {
+            text.push_str(&format!("\noutput: {}", value));
+        }
+        Ok(format!("{}\n{}", text, self.status()))
+    }
+
+    fn run_command(&mut self, line: &str) -> Result<String, String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(String::new()),
+        };
+        match command {
+            "s" | "step" => {
+                let count: usize = match words.next() {
+                    Some(word) => parse_number(Some(word), "count")?,
+                    None => 1,
+                };
+                self.with_outputs(|dbg| {
+                    for _ in 0..count {
+                        match dbg.step()? {
+                            Some(StopReason::Halted) => return Ok("halted".to_string()),
+                            Some(StopReason::NeedsInput) => {
+                                return Ok(dbg.describe_pause(Pause::NeedsInput))
+                            }
+                            _ => {}
+                        }
+                    }
+                    Ok(String::new())
+                })
+                .map(|text| text.trim_start().to_string())
+            }
+            "c" | "continue" => self.with_outputs(|dbg| {
+                let pause = dbg.cont()?;
+                Ok(dbg.describe_pause(pause))
+            }),
+            "back" => {
+                let count: usize = match words.next() {
+                    Some(word) => parse_number(Some(word), "count")?,
+                    None => 1,
+                };
+                let mut undone = 0;
+                while undone < count && self.history.step_back(&mut self.program).is_some() {
+                    undone += 1;
+                }
+                Ok(format!("back {}\n{}", undone, self.status()))
+            }
+            "backto" => {
+                let address: usize = parse_number(words.next(), "address")?;
+                match self.history.run_back_to(&mut self.program, address) {
+                    Some(undone) => Ok(format!("back {}\n{}", undone, self.status())),
+                    None => Err(format!(
+                        "{} never ran, undid all history\n{}",
+                        address,
+                        self.status()
+                    )),
+                }
+            }
+            "who" => {
+                let address: usize = parse_number(words.next(), "address")?;
+                match self.history.last_write(address) {
+                    Some((step, undo)) => {
+                        let (_, old) = undo.write.unwrap();
+                        Ok(format!(
+                            "step {}: {}  [{}] {} -> {}",
+                            step,
+                            self.current_instruction(undo.address),
+                            address,
+                            old,
+                            self.program.peek(address)
+                        ))
+                    }
+                    None => Ok(format!("no recorded write to {}", address)),
+                }
+            }
+            "b" | "break" => match words.next() {
+                Some(word) => match Opcode::from_mnemonic(word) {
+                    Some(opcode) => {
+                        self.add_opcode_breakpoint(opcode);
+                        Ok(format!("break on {}", opcode.mnemonic()))
+                    }
+                    None => {
+                        let address = parse_number(Some(word), "address")?;
+                        self.add_breakpoint(address);
+                        Ok(format!("breakpoint at {}", address))
+                    }
+                },
+                None => Err("break needs an address or mnemonic".to_string()),
+            },
+            "d" | "delete" => match words.next() {
+                Some(word) => match Opcode::from_mnemonic(word) {
+                    Some(opcode) => {
+                        self.opcode_breaks.remove(&opcode);
+                        Ok(format!("removed break on {}", opcode.mnemonic()))
+                    }
+                    None => {
+                        let address = parse_number(Some(word), "address")?;
+                        self.breakpoints.remove(&address);
+                        Ok(format!("removed breakpoint at {}", address))
+                    }
+                },
+                None => Err("delete needs an address or mnemonic".to_string()),
+            },
+            "bl" | "breaks" => {
+                let mut lines: Vec<String> = self
+                    .breakpoints
+                    .iter()
+                    .map(|address| format!("at {}", address))
+                    .collect();
+                lines.extend(
+                    self.opcode_breaks
+                        .iter()
+                        .map(|opcode| format!("on {}", opcode.mnemonic())),
+                );
+                if lines.is_empty() {
+                    Ok("no breakpoints".to_string())
+                } else {
+                    Ok(lines.join("\n"))
+                }
+            }
+            "i" | "info" => Ok(self.status()),
+            "x" | "peek" => {
+                let address: usize = parse_number(words.next(), "address")?;
+                let count = parse_count(words.next(), 1)?;
+                let end = address.checked_add(count).ok_or_else(|| {
+                    format!("{} cells from {} is past the end of memory", count, address)
+                })?;
+                Ok((address..end)
+                    .map(|a| format!("{:>6}: {}", a, self.program.peek(a)))
+                    .collect::<Vec<String>>()
+                    .join("\n"))
+            }
+            "poke" => {
+                let address: usize = parse_number(words.next(), "address")?;
+                let value: i128 = parse_number(words.next(), "value")?;
+                self.program
+                    .poke(address, value)
+                    .map_err(|err| format!("error: {}", err))?;
+                Ok(format!("{:>6}: {}", address, value))
+            }
+            "in" => {
+                let rest: Vec<&str> = words.collect();
+                let values = super::parse_values(&rest.join(" "))
+                    .map_err(|err| format!("error: {}", err))?;
+                if values.is_empty() {
+                    return Err("in needs at least one value".to_string());
+                }
+                for value in values.iter() {
+                    self.program.push_input(*value);
+                }
+                Ok(format!("input {:?}", self.program.pending_input()))
+            }
+            "out" => Ok(format!("{:?}", self.program.drain_outputs())),
+            "dis" => {
+                let mut address: usize = match words.next() {
+                    Some(word) => parse_number(Some(word), "address")?,
+                    None => self.program.inst_ptr(),
+                };
+                let count = parse_count(words.next(), 5)?;
+                let mut lines = vec![];
+                for _ in 0..count {
+                    lines.push(self.current_instruction(address));
+                    let width = match self.program.decode_at(address) {
+                        Ok(inst) => inst.width(),
+                        Err(_) => 1,
+                    };
+                    // stop at the end of memory
+                    address = match address.checked_add(width) {
+                        Some(next) => next,
+                        None => break,
+                    };
+                }
+                Ok(lines.join("\n"))
+            }
+            "q" | "quit" => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            "h" | "help" | "?" => Ok(HELP.to_string()),
+            _ => Err(format!("unknown command {:?}, try help", command)),
+        }
+    }
+
+    // Runs one command line and returns what to show the user.
+    pub fn execute(&mut self, line: &str) -> String {
+        match self.run_command(line) {
+            Ok(text) => text,
+            Err(text) => text,
+        }
+    }
+
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> std::io::Result<()> {
+        writeln!(output, "{}", self.status())?;
+        let mut lines = input.lines();
+        while !self.quit {
+            write!(output, "(icd) ")?;
+            output.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+            let text = self.execute(&line);
+            if !text.is_empty() {
+                writeln!(output, "{}", text)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::testing::DOUBLER;
+
+    fn debugger() -> Debugger {
+        Debugger::new(Program::new(DOUBLER, "").unwrap())
+    }
+
+    #[test]
+    fn continue_to_input_and_push() {
+        let mut dbg = debugger();
+        assert_eq!(dbg.cont().unwrap(), Pause::NeedsInput);
+        assert!(dbg.execute("c").starts_with("needs input"));
+        assert_eq!(dbg.execute("in 4, 5"), "input [4, 5]");
+        let text = dbg.execute("c");
+        assert!(text.starts_with("needs input, push some with `in`\noutput: 8\noutput: 10\n"));
+        assert_eq!(dbg.execute("out"), "[8, 10]");
+        dbg.execute("in 0");
+        assert!(dbg.execute("continue").starts_with("halted\n"));
+    }
+
+    #[test]
+    fn address_breakpoint() {
+        let mut dbg = debugger();
+        dbg.execute("in 3");
+        assert_eq!(dbg.execute("b 9"), "breakpoint at 9");
+        assert_eq!(dbg.cont().unwrap(), Pause::Breakpoint(9));
+        assert_eq!(dbg.program.peek(16), 6);
+        assert!(dbg.program.queued_outputs().is_empty());
+        dbg.execute("in 1");
+        assert_eq!(dbg.cont().unwrap(), Pause::Breakpoint(9));
+        assert_eq!(dbg.program.queued_outputs(), vec![6]);
+        dbg.execute("delete 9");
+        assert_eq!(dbg.execute("bl"), "no breakpoints");
+        assert_eq!(dbg.cont().unwrap(), Pause::NeedsInput);
+    }
+
+    #[test]
+    fn opcode_breakpoint() {
+        let mut dbg = debugger();
+        dbg.execute("in 3");
+        assert_eq!(dbg.execute("break out"), "break on OUT");
+        assert_eq!(dbg.cont().unwrap(), Pause::OpcodeBreak(Opcode::Out, 9));
+        assert_eq!(dbg.execute("breaks"), "on OUT");
+    }
+
+    #[test]
+    fn step_and_info() {
+        let mut dbg = debugger();
+        dbg.execute("in 2");
+        assert_eq!(
+            dbg.execute("s"),
+            "ip 2  rb 0  input []  output []\n     2: JZ [15], #14"
+        );
+        let text = dbg.execute("step 3");
+        assert!(text.starts_with("output: 4\nip 11"));
+        assert!(dbg.execute("s 5").starts_with("needs input"));
+        assert!(dbg.execute("i").contains("(needs input)"));
+    }
+
+    #[test]
+    fn reverse() {
+        let mut dbg = debugger();
+        dbg.execute("in 3");
+        dbg.execute("b 9");
+        assert_eq!(dbg.cont().unwrap(), Pause::Breakpoint(9));
+        assert_eq!(
+            dbg.execute("who 16"),
+            "step 2:      5: MUL [15], #2, [16]  [16] 0 -> 6"
+        );
+        assert_eq!(dbg.execute("who 17"), "no recorded write to 17");
+        assert!(dbg.execute("back").starts_with("back 1\nip 5 "));
+        assert_eq!(dbg.program.peek(16), 0);
+        assert!(dbg
+            .execute("backto 0")
+            .starts_with("back 2\nip 0  rb 0  input [3]"));
+        assert!(dbg.execute("backto 9").starts_with("9 never ran"));
+        assert!(dbg.execute("back 5").starts_with("back 0\n"));
+        assert_eq!(dbg.cont().unwrap(), Pause::Breakpoint(9));
+    }
+
+    #[test]
+    fn memory_commands() {
+        let mut dbg = debugger();
+        assert_eq!(dbg.execute("x 0 2"), "     0: 3\n     1: 15");
+        assert_eq!(dbg.execute("poke 20000 7"), " 20000: 7");
+        assert_eq!(dbg.execute("peek 20000"), " 20000: 7");
+        assert_eq!(
+            dbg.execute("dis 0 2"),
+            "     0: IN [15]\n     2: JZ [15], #14"
+        );
+        assert_eq!(dbg.execute("dis 15 1"), "    15: .data 0");
+    }
+
+    #[test]
+    fn bad_commands() {
+        let mut dbg = debugger();
+        assert_eq!(dbg.execute("frob"), "unknown command \"frob\", try help");
+        assert_eq!(dbg.execute("b x1"), "bad address \"x1\"");
+        assert_eq!(dbg.execute("poke 1"), "missing value");
+        assert_eq!(dbg.execute("in"), "in needs at least one value");
+        assert_eq!(
+            dbg.execute(&format!("x {} 2", usize::MAX)),
+            format!("2 cells from {} is past the end of memory", usize::MAX)
+        );
+        assert_eq!(dbg.execute("x 0 1001"), "count 1001 is more than 1000");
+        assert_eq!(dbg.execute("dis 0 5000"), "count 5000 is more than 1000");
+        assert_eq!(
+            dbg.execute(&format!("dis {} 3", usize::MAX)),
+            format!("{}: .data 0", usize::MAX)
+        );
+        assert_eq!(dbg.execute(""), "");
+    }
+
+    #[test]
+    fn repl() {
+        let mut dbg = debugger();
+        let input = std::io::Cursor::new("in 21\nc\nq\ns\n");
+        let mut output = Vec::new();
+        dbg.repl(input, &mut output).unwrap();
+        let text = String::from_utf8(output).unwrap();
+        assert!(text.contains("(icd) input [21]\n"));
+        assert!(text.contains("output: 42\n"));
+        assert!(dbg.is_quit());
+        assert_eq!(dbg.program.inst_ptr(), 0);
+    }
+}
@@ -0,0 +1,337 @@
+use super::{decode, Instruction, Mode, Opcode};
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+
+const DATA_PER_LINE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    Code(Instruction),
+    Data(Vec<i128>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub address: usize,
+    pub kind: Kind,
+}
+
+// A disassembled program image. Code is found by following execution from
+// address 0 through fall-through and immediate jump targets, plus any
+// immediate that gets copied into memory with ADD #0 / MUL #1 and decodes
+// as an instruction (the usual way puzzle programs push return addresses).
+// Everything else is treated as data.
+pub struct Listing {
+    pub lines: Vec<Line>,
+    pub labels: BTreeMap<usize, String>,
+    // (instruction address, param index) of immediates shown as labels
+    pointers: HashSet<(usize, usize)>,
+    // addresses that start a decoded instruction
+    code: HashSet<usize>,
+}
+
+impl Listing {
+    pub fn is_code(&self, address: usize) -> bool {
+        self.code.contains(&address)
+    }
+
+    pub fn label(&self, address: usize) -> Option<&str> {
+        self.labels.get(&address).map(|s| s.as_str())
+    }
+
+    pub fn format_instruction(&self, address: usize, inst: &Instruction) -> String {
+        let mut text = inst.opcode.mnemonic().to_string();
+        for (i, param) in inst.params().iter().enumerate() {
+            text.push_str(if i == 0 { " " } else { ", " });
+            let label = if param.value >= 0 {
+                self.labels.get(&(param.value as usize))
+            } else {
+                None
+            };
+            match (param.mode, label) {
+                (Mode::Immediate, Some(label)) if self.pointers.contains(&(address, i)) => {
+                    text.push_str(&format!("#{}", label))
+                }
+                (Mode::Position, Some(label)) if !self.is_code(param.value as usize) => {
+                    text.push_str(&format!("[{}]", label))
+                }
+                _ => text.push_str(&format!("{}", param)),
+            }
+        }
+        text
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in self.lines.iter() {
+            if let Some(label) = self.labels.get(&line.address) {
+                writeln!(f, "{}:", label)?;
+            }
+            match &line.kind {
+                Kind::Code(inst) => writeln!(
+                    f,
+                    "{:>6}: {}",
+                    line.address,
+                    self.format_instruction(line.address, inst)
+                )?,
+                Kind::Data(values) => {
+                    let values: Vec<String> = values.iter().map(|v| format!("{}", v)).collect();
+                    writeln!(f, "{:>6}: .data {}", line.address, values.join(", "))?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read(image: &[i128]) -> impl Fn(usize) -> i128 + '_ {
+    move |address| image.get(address).copied().unwrap_or(0)
+}
+
+// the immediate copied into memory by ADD #0, #v / MUL #1, #v (either order)
+fn copied_immediate(inst: &Instruction) -> Option<(usize, i128)> {
+    let identity = match inst.opcode {
+        Opcode::Add => 0,
+        Opcode::Mul => 1,
+        _ => return None,
+    };
+    let params = inst.params();
+    if params[0].mode != Mode::Immediate || params[1].mode != Mode::Immediate {
+        return None;
+    }
+    if params[0].value == identity {
+        Some((1, params[1].value))
+    } else if params[1].value == identity {
+        Some((0, params[0].value))
+    } else {
+        None
+    }
+}
+
+// addresses execution can continue at after inst, ignoring indirect jumps
+fn successors(address: usize, inst: &Instruction) -> Vec<usize> {
+    let params = inst.params();
+    let next = address + inst.width();
+    match inst.opcode {
+        Opcode::Hlt => vec![],
+        Opcode::Jnz | Opcode::Jz => {
+            let mut succ = vec![];
+            let cond = params[0];
+            let always =
+                cond.mode == Mode::Immediate && ((inst.opcode == Opcode::Jnz) == (cond.value != 0));
+            let never = cond.mode == Mode::Immediate && !always;
+            if !always {
+                succ.push(next);
+            }
+            if !never && params[1].mode == Mode::Immediate && params[1].value >= 0 {
+                succ.push(params[1].value as usize);
+            }
+            succ
+        }
+        _ => vec![next],
+    }
+}
+
+pub fn disassemble(image: &[i128]) -> Listing {
+    let mut starts: BTreeMap<usize, Instruction> = BTreeMap::new();
+    let mut covered: Vec<bool> = vec![false; image.len()];
+    let mut code_labels: BTreeSet<usize> = BTreeSet::new();
+    let mut pointers = HashSet::new();
+    let mut todo = if image.is_empty() { vec![] } else { vec![0] };
+
+    while let Some(address) = todo.pop() {
+        if starts.contains_key(&address) || address >= image.len() {
+            continue;
+        }
+        let inst = match decode(read(image), address) {
+            Ok(inst) => inst,
+            Err(_) => continue,
+        };
+        let end = address + inst.width();
+        if end > image.len() || covered[address..end].iter().any(|c| *c) {
+            continue;
+        }
+        for cell in covered[address..end].iter_mut() {
+            *cell = true;
+        }
+        starts.insert(address, inst);
+
+        if inst.opcode.is_jump() {
+            let target = inst.params()[1];
+            if target.mode == Mode::Immediate && target.value >= 0 {
+                code_labels.insert(target.value as usize);
+                pointers.insert((address, 1));
+            }
+        }
+        if let Some((index, value)) = copied_immediate(&inst) {
+            let target = value as usize;
+            if value >= 0 && target < image.len() && decode(read(image), target).is_ok() {
+                code_labels.insert(target);
+                pointers.insert((address, index));
+                todo.push(target);
+            }
+        }
+        todo.extend(successors(address, &inst));
+    }
+
+    let mut labels = BTreeMap::new();
+    for address in code_labels {
+        if starts.contains_key(&address) {
+            labels.insert(address, format!("L{}", address));
+        }
+    }
+    for inst in starts.values() {
+        for param in inst.params() {
+            if param.mode == Mode::Position
+                && param.value >= 0
+                && (param.value as usize) < image.len()
+                && !covered[param.value as usize]
+            {
+                labels.insert(param.value as usize, format!("D{}", param.value));
+            }
+        }
+    }
+    pointers.retain(|(address, index)| {
+        let value = starts[address].params()[*index].value;
+        value >= 0 && labels.contains_key(&(value as usize))
+    });
+
+    let code = starts.keys().copied().collect();
+    let mut lines = vec![];
+    let mut address = 0;
+    while address < image.len() {
+        if let Some(inst) = starts.get(&address) {
+            lines.push(Line {
+                address,
+                kind: Kind::Code(*inst),
+            });
+            address += inst.width();
+            continue;
+        }
+        let mut values = vec![image[address]];
+        let mut next = address + 1;
+        while next < image.len()
+            && values.len() < DATA_PER_LINE
+            && !starts.contains_key(&next)
+            && !labels.contains_key(&next)
+        {
+            values.push(image[next]);
+            next += 1;
+        }
+        lines.push(Line {
+            address,
+            kind: Kind::Data(values),
+        });
+        address = next;
+    }
+
+    Listing {
+        lines,
+        labels,
+        pointers,
+        code,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::parse_values;
+
+    #[test]
+    fn straight_line() {
+        let image = parse_values("1, 9, 10, 3, 2, 3, 11, 0, 99, 30, 40, 50").unwrap();
+        let listing = disassemble(&image);
+        assert_eq!(
+            format!("{}", listing),
+            "     0: ADD [D9], [D10], [3]\n\
+             \x20    4: MUL [3], [D11], [0]\n\
+             \x20    8: HLT\n\
+             D9:\n\
+             \x20    9: .data 30\n\
+             D10:\n\
+             \x20   10: .data 40\n\
+             D11:\n\
+             \x20   11: .data 50\n"
+        );
+    }
+
+    #[test]
+    fn jumps_and_labels() {
+        // input, jump to 9 if zero, output, halt
+        let image = parse_values("3, 12, 1006, 12, 9, 4, 12, 99, 7, 104, 0, 99, 0").unwrap();
+        let listing = disassemble(&image);
+        assert_eq!(
+            format!("{}", listing),
+            "     0: IN [D12]\n\
+             \x20    2: JZ [D12], #L9\n\
+             \x20    5: OUT [D12]\n\
+             \x20    7: HLT\n\
+             \x20    8: .data 7\n\
+             L9:\n\
+             \x20    9: OUT #0\n\
+             \x20   11: HLT\n\
+             D12:\n\
+             \x20   12: .data 0\n"
+        );
+        assert!(listing.is_code(9));
+        assert!(!listing.is_code(8));
+        assert_eq!(listing.label(9), Some("L9"));
+    }
+
+    #[test]
+    fn unconditional_jump_skips_data() {
+        let image = parse_values("1105, 1, 5, 42, 43, 99").unwrap();
+        let listing = disassemble(&image);
+        assert_eq!(
+            listing.lines,
+            vec![
+                Line {
+                    address: 0,
+                    kind: Kind::Code(decode(read(&image), 0).unwrap())
+                },
+                Line {
+                    address: 3,
+                    kind: Kind::Data(vec![42, 43])
+                },
+                Line {
+                    address: 5,
+                    kind: Kind::Code(decode(read(&image), 5).unwrap())
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn pushed_return_address() {
+        // push return address 9, jump to the subroutine at 12 which returns
+        // through rb+0
+        let image =
+            parse_values("109, 20, 21101, 0, 9, 0, 1105, 1, 12, 99, 0, 0, 2106, 0, 0").unwrap();
+        let listing = disassemble(&image);
+        let text = format!("{}", listing);
+        assert!(text.contains("     2: ADD #0, #L9, rb+0\n"));
+        assert!(text.contains("L9:\n     9: HLT\n"));
+        assert!(text.contains("L12:\n    12: JZ #0, rb+0\n"));
+    }
+
+    #[test]
+    fn quine() {
+        let image =
+            parse_values("109,1,204,-1,1001,100,1,100,1008,100,16,101,1006,101,0,99").unwrap();
+        let listing = disassemble(&image);
+        assert_eq!(
+            format!("{}", listing),
+            "L0:\n\
+             \x20    0: ARB #1\n\
+             \x20    2: OUT rb-1\n\
+             \x20    4: ADD [100], #1, [100]\n\
+             \x20    8: EQ [100], #16, [101]\n\
+             \x20   12: JZ [101], #L0\n\
+             \x20   15: HLT\n"
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use super::{Executed, Observer, Program};
+
+use std::collections::VecDeque;
+
+// An undo log for running programs backwards. Only what each executed
+// instruction changed is kept (the old value of the cell it wrote, the old
+// relative base, whether it consumed input):
+//
+//     let mut history = History::new();
+//     prog.run_observed(&mut history)?;
+//     history.step_back(&mut prog);
+//
+// Undoing only works on the program the history was recorded from, and
+// only while nothing else has changed it. Outputs stay produced.
+
+// What it takes to undo one instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Undo {
+    // where the instruction was, and so where to go back to
+    pub address: usize,
+    // the cell it wrote and the value that was there before
+    pub write: Option<(usize, i128)>,
+    // the relative base before, if the instruction changed it
+    pub relative_base: Option<usize>,
+    pub input: bool,
+}
+
+impl Undo {
+    fn new(executed: &Executed) -> Undo {
+        Undo {
+            address: executed.address,
+            write: executed.write.map(|write| (write.address, write.old)),
+            relative_base: executed.relative_base.map(|(old, _)| old),
+            input: executed.input.is_some(),
+        }
+    }
+
+    fn revert(&self, prog: &mut Program) {
+        if let Some((address, old)) = self.write {
+            prog.memory.set(address, old);
+        }
+        if self.input {
+            prog.input_ptr -= 1;
+        }
+        if let Some(old) = self.relative_base {
+            prog.relative_base = old;
+        }
+        prog.inst_ptr = self.address;
+        prog.done = false;
+        prog.input_needed = false;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    entries: VecDeque<Undo>,
+    // drop the oldest entries past this many
+    limit: Option<usize>,
+    // number of the step after the last entry
+    steps: u64,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    pub fn with_limit(limit: usize) -> History {
+        History {
+            limit: Some(limit),
+            ..History::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // how many instructions have run, counting ones dropped for the limit
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // steps numbered from 0, oldest first
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = (u64, &Undo)> {
+        let first = self.steps - self.entries.len() as u64;
+        self.entries
+            .iter()
+            .enumerate()
+            .map(move |(i, executed)| (first + i as u64, executed))
+    }
+
+    pub fn last(&self) -> Option<&Undo> {
+        self.entries.back()
+    }
+
+    // Undoes the most recent instruction, returning its record.
+    pub fn step_back(&mut self, prog: &mut Program) -> Option<Undo> {
+        let undo = self.entries.pop_back()?;
+        undo.revert(prog);
+        self.steps -= 1;
+        Some(undo)
+    }
+
+    // Steps back until the instruction at address is next to run again.
+    // Returns the number of steps undone, or None (leaving prog as it is)
+    // if it never ran.
+    pub fn run_back_to(&mut self, prog: &mut Program, address: usize) -> Option<u64> {
+        let ran = self.entries().any(|(_, undo)| undo.address == address);
+        if !ran {
+            return None;
+        }
+        let mut undone = 0;
+        while let Some(undo) = self.step_back(prog) {
+            undone += 1;
+            if undo.address == address {
+                return Some(undone);
+            }
+        }
+        None
+    }
+
+    // The most recent recorded instruction that wrote address. What it
+    // wrote is what the cell holds now, unless it was changed by hand.
+    pub fn last_write(&self, address: usize) -> Option<(u64, &Undo)> {
+        self.entries()
+            .rev()
+            .find(|(_, undo)| matches!(undo.write, Some((written, _)) if written == address))
+    }
+}
+
+impl Observer for History {
+    fn observe(&mut self, _prog: &Program, executed: &Executed) {
+        self.steps += 1;
+        match self.limit {
+            Some(0) => return,
+            Some(limit) if self.entries.len() == limit => {
+                self.entries.pop_front();
+            }
+            _ => {}
+        }
+        self.entries.push_back(Undo::new(executed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::testing::{run_observed_to_end, DOUBLER};
+    use super::super::StopReason;
+
+    #[test]
+    fn step_back_restores_state() {
+        let mut prog = Program::new(DOUBLER, "4, 5").unwrap();
+        let start = prog.clone();
+        let mut history = History::new();
+        let mut states = vec![];
+        loop {
+            states.push(prog.clone());
+            if prog.step_observed(&mut history).unwrap() == Some(StopReason::NeedsInput) {
+                states.pop();
+                break;
+            }
+        }
+        assert_eq!(history.len(), states.len());
+        assert_eq!(history.steps(), 10);
+        while let Some(state) = states.pop() {
+            history.step_back(&mut prog).unwrap();
+            assert!(prog.same_state(&state));
+        }
+        assert!(history.step_back(&mut prog).is_none());
+        assert!(prog.same_state(&start));
+        assert_eq!(prog.pending_input(), &[4, 5]);
+    }
+
+    #[test]
+    fn run_back_to_and_last_write() {
+        let mut prog = Program::new(DOUBLER, "4, 5, 0").unwrap();
+        let mut history = History::new();
+        assert_eq!(
+            run_observed_to_end(&mut prog, &mut history),
+            StopReason::Halted
+        );
+
+        let (step, undo) = history.last_write(16).unwrap();
+        assert_eq!((step, undo.address), (7, 5));
+        assert_eq!(undo.write, Some((16, 8)));
+        let (step, undo) = history.last_write(15).unwrap();
+        assert_eq!((step, undo.address), (10, 0));
+        assert!(history.last_write(3).is_none());
+
+        // back to before the second doubling
+        assert_eq!(history.run_back_to(&mut prog, 5), Some(6));
+        assert_eq!(prog.inst_ptr(), 5);
+        assert_eq!((prog.peek(15), prog.peek(16)), (5, 8));
+        assert_eq!(prog.pending_input(), &[0]);
+        assert_eq!(history.last_write(16).unwrap().0, 2);
+
+        // and forwards again
+        assert_eq!(
+            prog.run_observed(&mut history).unwrap(),
+            StopReason::Output(10)
+        );
+        let (len, inst_ptr) = (history.len(), prog.inst_ptr());
+        assert_eq!(history.run_back_to(&mut prog, 99), None);
+        assert_eq!((history.len(), prog.inst_ptr()), (len, inst_ptr));
+    }
+
+    #[test]
+    fn limit() {
+        let mut prog = Program::new(DOUBLER, "4, 5, 0").unwrap();
+        let mut history = History::with_limit(3);
+        assert_eq!(
+            run_observed_to_end(&mut prog, &mut history),
+            StopReason::Halted
+        );
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.steps(), 13);
+        let steps: Vec<u64> = history.entries().map(|(step, _)| step).collect();
+        assert_eq!(steps, vec![10, 11, 12]);
+        assert_eq!(history.run_back_to(&mut prog, 0), Some(3));
+
+        let mut prog = Program::new(DOUBLER, "4, 5, 0").unwrap();
+        let mut history = History::with_limit(0);
+        assert_eq!(
+            run_observed_to_end(&mut prog, &mut history),
+            StopReason::Halted
+        );
+        assert!(history.is_empty());
+        assert_eq!(history.steps(), 13);
+    }
+}
@@ -0,0 +1,383 @@
+use super::ascii::as_char;
+use super::{IntcodeError, Program, StopReason, Word};
+
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+use std::sync::mpsc::{Receiver, Sender};
+
+// Something a Program can read from when it runs out of queued input.
+// Returning None means nothing is available and the machine should stop
+// and report NeedsInput.
+pub trait InputSource {
+    fn next_input(&mut self) -> Option<i128>;
+}
+
+pub trait OutputSink {
+    fn write_output(&mut self, value: i128);
+}
+
+impl InputSource for VecDeque<i128> {
+    fn next_input(&mut self) -> Option<i128> {
+        self.pop_front()
+    }
+}
+
+impl InputSource for std::vec::IntoIter<i128> {
+    fn next_input(&mut self) -> Option<i128> {
+        self.next()
+    }
+}
+
+impl<F> InputSource for F
+where
+    F: FnMut() -> Option<i128>,
+{
+    fn next_input(&mut self) -> Option<i128> {
+        self()
+    }
+}
+
+// Blocks until a value arrives; a hung up sender counts as no more input.
+impl InputSource for Receiver<i128> {
+    fn next_input(&mut self) -> Option<i128> {
+        self.recv().ok()
+    }
+}
+
+impl OutputSink for Vec<i128> {
+    fn write_output(&mut self, value: i128) {
+        self.push(value);
+    }
+}
+
+impl OutputSink for VecDeque<i128> {
+    fn write_output(&mut self, value: i128) {
+        self.push_back(value);
+    }
+}
+
+impl<F> OutputSink for F
+where
+    F: FnMut(i128),
+{
+    fn write_output(&mut self, value: i128) {
+        self(value)
+    }
+}
+
+// Outputs sent after the receiver has gone away are dropped.
+impl OutputSink for Sender<i128> {
+    fn write_output(&mut self, value: i128) {
+        let _ = self.send(value);
+    }
+}
+
+// Reads comma or whitespace separated numbers a line at a time. Tokens that
+// don't parse are reported on stderr and skipped.
+pub struct TextInput<R> {
+    reader: R,
+    pending: VecDeque<i128>,
+}
+
+impl<R: BufRead> TextInput<R> {
+    pub fn new(reader: R) -> TextInput<R> {
+        TextInput {
+            reader,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+pub fn stdin() -> TextInput<std::io::BufReader<std::io::Stdin>> {
+    TextInput::new(std::io::BufReader::new(std::io::stdin()))
+}
+
+impl<R: BufRead> InputSource for TextInput<R> {
+    fn next_input(&mut self) -> Option<i128> {
+        while self.pending.is_empty() {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => {}
+            }
+            for token in line.split(|c: char| c == ',' || c.is_whitespace()) {
+                if token.is_empty() {
+                    continue;
+                }
+                match token.parse() {
+                    Ok(value) => self.pending.push_back(value),
+                    Err(_) => eprintln!("ignoring bad input {:?}", token),
+                }
+            }
+        }
+        self.pending.pop_front()
+    }
+}
+
+// Writes each output on its own line.
+pub struct TextOutput<W> {
+    writer: W,
+}
+
+impl<W: Write> TextOutput<W> {
+    pub fn new(writer: W) -> TextOutput<W> {
+        TextOutput { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+pub fn stdout() -> TextOutput<std::io::Stdout> {
+    TextOutput::new(std::io::stdout())
+}
+
+impl<W: Write> OutputSink for TextOutput<W> {
+    fn write_output(&mut self, value: i128) {
+        let _ = writeln!(self.writer, "{}", value);
+        let _ = self.writer.flush();
+    }
+}
+
+// Feeds each line read as character codes, newline included.
+pub struct AsciiInput<R> {
+    reader: R,
+    pending: VecDeque<i128>,
+}
+
+impl<R: BufRead> AsciiInput<R> {
+    pub fn new(reader: R) -> AsciiInput<R> {
+        AsciiInput {
+            reader,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<R: BufRead> InputSource for AsciiInput<R> {
+    fn next_input(&mut self) -> Option<i128> {
+        if self.pending.is_empty() {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => {}
+            }
+            let line = line.trim_end_matches(&['\n', '\r'][..]);
+            self.pending.extend(line.bytes().map(i128::from));
+            self.pending.push_back(10);
+        }
+        self.pending.pop_front()
+    }
+}
+
+// Writes outputs as text. Anything that isn't an ASCII character code
+// (the answer most ASCII programs end with) goes on a line of its own as a
+// number.
+pub struct AsciiOutput<W> {
+    writer: W,
+    at_line_start: bool,
+}
+
+impl<W: Write> AsciiOutput<W> {
+    pub fn new(writer: W) -> AsciiOutput<W> {
+        AsciiOutput {
+            writer,
+            at_line_start: true,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> OutputSink for AsciiOutput<W> {
+    fn write_output(&mut self, value: i128) {
+        let _ = match as_char(value) {
+            Some(c) => {
+                self.at_line_start = c == '\n';
+                write!(self.writer, "{}", c)
+            }
+            None if self.at_line_start => writeln!(self.writer, "{}", value),
+            None => {
+                self.at_line_start = true;
+                writeln!(self.writer, "\n{}", value)
+            }
+        };
+        let _ = self.writer.flush();
+    }
+}
+
+// A Program wired to an input source and an output sink, so it can be run
+// without the caller shuttling values in and out.
+pub struct Machine<I, O, W: Word = i128> {
+    pub program: Program<W>,
+    pub input: I,
+    pub output: O,
+}
+
+impl<I: InputSource, O: OutputSink> Machine<I, O> {
+    pub fn from_code(code: &str, input: I, output: O) -> Result<Machine<I, O>, IntcodeError> {
+        Ok(Machine::new(Program::new(code, "")?, input, output))
+    }
+}
+
+impl<I: InputSource, O: OutputSink, W: Word> Machine<I, O, W> {
+    pub fn new(program: Program<W>, input: I, output: O) -> Machine<I, O, W> {
+        Machine {
+            program,
+            input,
+            output,
+        }
+    }
+
+    // Runs until the program halts, or needs input that the source can't
+    // supply.
+    pub fn run(&mut self) -> Result<StopReason, IntcodeError> {
+        for value in self.program.drain_outputs() {
+            self.output.write_output(value);
+        }
+        loop {
+            match self.program.run_until_event()? {
+                StopReason::Output(value) => self.output.write_output(value),
+                StopReason::NeedsInput => match self.input.next_input() {
+                    Some(value) => self.program.push_input(value),
+                    None => return Ok(StopReason::NeedsInput),
+                },
+                reason => return Ok(reason),
+            }
+        }
+    }
+
+    pub fn into_parts(self) -> (Program<W>, I, O) {
+        (self.program, self.input, self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::testing::DOUBLER;
+
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    #[test]
+    fn vec_io() {
+        let input: VecDeque<i128> = vec![1, 2, 3, 0].into_iter().collect();
+        let mut machine = Machine::from_code(DOUBLER, input, Vec::new()).unwrap();
+        assert_eq!(machine.run().unwrap(), StopReason::Halted);
+        assert_eq!(machine.output, vec![2, 4, 6]);
+    }
+
+    #[test]
+    fn vec_into_iter_input() {
+        let prog = Program::new(DOUBLER, "").unwrap();
+        let mut machine = prog.with_io(vec![5].into_iter(), VecDeque::new());
+        assert_eq!(machine.run().unwrap(), StopReason::NeedsInput);
+        assert_eq!(machine.output, vec![10]);
+        machine.program.push_input(0);
+        assert_eq!(machine.run().unwrap(), StopReason::Halted);
+    }
+
+    #[test]
+    fn closure_io() {
+        let mut next = 3;
+        let mut total = 0;
+        let input = || {
+            next -= 1;
+            Some(next)
+        };
+        let output = |value| total += value;
+        let mut machine = Machine::from_code(DOUBLER, input, output).unwrap();
+        assert_eq!(machine.run().unwrap(), StopReason::Halted);
+        drop(machine);
+        assert_eq!(total, 6);
+    }
+
+    #[test]
+    fn other_word_types() {
+        let prog = Program::<i64>::parse(DOUBLER, "").unwrap();
+        let mut machine = prog.with_io(vec![4, 0].into_iter(), Vec::new());
+        assert_eq!(machine.run().unwrap(), StopReason::Halted);
+        assert_eq!(machine.output, vec![8]);
+    }
+
+    #[test]
+    fn queued_outputs_are_flushed() {
+        let mut prog = Program::new("104, 7, 3, 0, 99", "").unwrap();
+        prog.run_until_blocked().unwrap();
+        let mut machine = prog.with_io(vec![1].into_iter(), Vec::new());
+        assert_eq!(machine.run().unwrap(), StopReason::Halted);
+        assert_eq!(machine.output, vec![7]);
+    }
+
+    #[test]
+    fn channel_io() {
+        let code =
+            "3,26,1001,26,-4,26,3,27,1002,27,2,27,1,27,26,27,4,27,1001,28,-1,28,1005,28,6,99,0,0,5";
+        let phases = [9, 8, 7, 6, 5];
+        let channels: Vec<_> = (0..5).map(|_| channel()).collect();
+        let (senders, receivers): (Vec<_>, Vec<_>) = channels.into_iter().unzip();
+        for (sender, phase) in senders.iter().zip(phases.iter()) {
+            sender.send(*phase).unwrap();
+        }
+        senders[0].send(0).unwrap();
+
+        let handles: Vec<_> = receivers
+            .into_iter()
+            .enumerate()
+            .map(|(i, receiver)| {
+                let sender = senders[(i + 1) % 5].clone();
+                let code = code.to_string();
+                thread::spawn(move || {
+                    let mut machine = Machine::from_code(&code, receiver, sender).unwrap();
+                    assert_eq!(machine.run().unwrap(), StopReason::Halted);
+                    machine
+                })
+            })
+            .collect();
+        drop(senders);
+        let machines: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        let signal = machines[0].input.try_iter().last();
+        assert_eq!(signal, Some(139629729));
+    }
+
+    #[test]
+    fn text_io() {
+        let reader = std::io::Cursor::new("4, 5\n\nx 6\n0\n");
+        let output = TextOutput::new(Vec::new());
+        let mut machine = Machine::from_code(DOUBLER, TextInput::new(reader), output).unwrap();
+        assert_eq!(machine.run().unwrap(), StopReason::Halted);
+        let (_, _, output) = machine.into_parts();
+        assert_eq!(
+            String::from_utf8(output.into_inner()).unwrap(),
+            "8\n10\n12\n"
+        );
+    }
+
+    #[test]
+    fn text_input_eof() {
+        let mut input = TextInput::new(std::io::Cursor::new("1,2"));
+        assert_eq!(input.next_input(), Some(1));
+        assert_eq!(input.next_input(), Some(2));
+        assert_eq!(input.next_input(), None);
+    }
+
+    #[test]
+    fn ascii_io() {
+        // echoes characters until it reads a '.', then outputs 1000
+        let code = "3, 17, 1008, 17, 46, 18, 1005, 18, 14, 4, 17, 1105, 1, 0, 104, 1000, 99, 0, 0";
+        let reader = std::io::Cursor::new("hi\r\nyo.\n");
+        let output = AsciiOutput::new(Vec::new());
+        let mut machine = Machine::from_code(code, AsciiInput::new(reader), output).unwrap();
+        assert_eq!(machine.run().unwrap(), StopReason::Halted);
+        let (_, _, output) = machine.into_parts();
+        assert_eq!(
+            String::from_utf8(output.into_inner()).unwrap(),
+            "hi\nyo\n1000\n"
+        );
+    }
+}
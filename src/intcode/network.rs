@@ -0,0 +1,387 @@
+use super::scheduler::MachineError;
+use super::{Program, StopReason, Word};
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+
+// A packet switched network of copies of one program. Each machine reads
+// its address when it boots, then sends packets as three outputs
+// (destination, x, y) and reads them as two inputs (x, y), or -1 when
+// nothing is waiting for it:
+//
+//     let mut network = Network::new(&prog, 50);
+//     network.set_nat(Box::new(ResendLast::default()));
+//     network.run()?;
+//
+// Packets to NAT_ADDRESS go to the NAT, which gets a chance to wake the
+// network up whenever it goes idle. Packets to any other address nobody
+// has are dropped. Every packet ends up in the log, apart from ones sent to
+// a negative address, which are kept with it in misaddressed instead.
+
+pub const NAT_ADDRESS: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet {
+    pub from: usize,
+    pub to: usize,
+    pub x: i128,
+    pub y: i128,
+}
+
+// a packet whose destination can't be an address, as it was sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Misaddressed {
+    pub from: usize,
+    pub to: i128,
+    pub x: i128,
+    pub y: i128,
+}
+
+// What the NAT does with the packets sent to it.
+pub trait NatPolicy {
+    fn receive(&mut self, packet: &Packet);
+    // Called when every queue is empty and no machine is sending. Returns
+    // the (x, y) to send machine 0, or None to stop the network.
+    fn on_idle(&mut self) -> Option<(i128, i128)>;
+}
+
+// Sends machine 0 the last packet it received, and stops the network
+// rather than send the same y twice in a row. The last packet from
+// NAT_ADDRESS in the log has the repeated y.
+#[derive(Debug, Clone, Default)]
+pub struct ResendLast {
+    last: Option<(i128, i128)>,
+    sent: Option<i128>,
+}
+
+impl NatPolicy for ResendLast {
+    fn receive(&mut self, packet: &Packet) {
+        self.last = Some((packet.x, packet.y));
+    }
+
+    fn on_idle(&mut self) -> Option<(i128, i128)> {
+        let (x, y) = self.last?;
+        if self.sent == Some(y) {
+            return None;
+        }
+        self.sent = Some(y);
+        Some((x, y))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    // went idle with no NAT to wake it
+    Idle,
+    // the NAT declined to wake it
+    NatStopped,
+    AllHalted,
+}
+
+pub struct Network<W: Word = i128> {
+    machines: Vec<Program<W>>,
+    queues: Vec<VecDeque<(i128, i128)>>,
+    // outputs of each machine that don't make up a whole packet yet
+    partial: Vec<Vec<i128>>,
+    log: Vec<Packet>,
+    misaddressed: Vec<Misaddressed>,
+    nat: Option<Box<dyn NatPolicy>>,
+}
+
+impl<W: Word> Network<W> {
+    // boots size copies of prog with addresses 0..size
+    pub fn new(prog: &Program<W>, size: usize) -> Network<W> {
+        let machines = (0..size)
+            .map(|address| {
+                let mut machine = prog.clone();
+                machine.push_input(address as i128);
+                machine
+            })
+            .collect();
+        Network {
+            machines,
+            queues: vec![VecDeque::new(); size],
+            partial: vec![vec![]; size],
+            log: vec![],
+            misaddressed: vec![],
+            nat: None,
+        }
+    }
+
+    pub fn set_nat(&mut self, nat: Box<dyn NatPolicy>) {
+        self.nat = Some(nat);
+    }
+
+    pub fn program(&self, address: usize) -> &Program<W> {
+        &self.machines[address]
+    }
+
+    pub fn log(&self) -> &[Packet] {
+        &self.log
+    }
+
+    pub fn misaddressed(&self) -> &[Misaddressed] {
+        &self.misaddressed
+    }
+
+    pub fn packets_to(&self, address: usize) -> impl Iterator<Item = &Packet> {
+        self.log.iter().filter(move |packet| packet.to == address)
+    }
+
+    // queues a packet from outside the network
+    pub fn send(&mut self, packet: Packet) {
+        self.log.push(packet);
+        if packet.to == NAT_ADDRESS {
+            if let Some(nat) = self.nat.as_mut() {
+                nat.receive(&packet);
+            }
+        } else if packet.to < self.machines.len() {
+            self.queues[packet.to].push_back((packet.x, packet.y));
+        }
+    }
+
+    // Runs machine until it wants input it hasn't got, sending any packets
+    // it makes. Returns how many it sent.
+    fn run_machine(&mut self, address: usize) -> Result<usize, MachineError> {
+        let mut sent = 0;
+        loop {
+            let reason =
+                self.machines[address]
+                    .run_until_event()
+                    .map_err(|error| MachineError {
+                        machine: address,
+                        error,
+                    })?;
+            let value = match reason {
+                StopReason::Output(value) => value,
+                _ => return Ok(sent),
+            };
+            let partial = &mut self.partial[address];
+            partial.push(value);
+            if partial.len() == 3 {
+                let (to, x, y) = (partial[0], partial[1], partial[2]);
+                partial.clear();
+                match usize::try_from(to) {
+                    Ok(to) => self.send(Packet {
+                        from: address,
+                        to,
+                        x,
+                        y,
+                    }),
+                    Err(_) => self.misaddressed.push(Misaddressed {
+                        from: address,
+                        to,
+                        x,
+                        y,
+                    }),
+                }
+                sent += 1;
+            }
+        }
+    }
+
+    // Gives every machine its waiting packets (or a -1) and runs it until
+    // it wants more. Returns whether anything was delivered or sent.
+    pub fn round(&mut self) -> Result<bool, MachineError> {
+        let mut busy = false;
+        for address in 0..self.machines.len() {
+            if self.machines[address].is_done() {
+                continue;
+            }
+            if self.machines[address].needs_input() {
+                match self.queues[address].pop_front() {
+                    Some((x, y)) => {
+                        self.machines[address].push_input(x);
+                        self.machines[address].push_input(y);
+                        busy = true;
+                    }
+                    None => self.machines[address].push_input(-1),
+                }
+            }
+            busy |= self.run_machine(address)? > 0;
+        }
+        Ok(busy || self.queues.iter().any(|queue| !queue.is_empty()))
+    }
+
+    // A round, then a word with the NAT if that left the network idle.
+    // Returns why the network stopped, if it has.
+    fn advance(&mut self) -> Result<Option<Stop>, MachineError> {
+        let busy = self.round()?;
+        if self.machines.iter().all(|machine| machine.is_done()) {
+            return Ok(Some(Stop::AllHalted));
+        }
+        if busy {
+            return Ok(None);
+        }
+        let wake = match self.nat.as_mut() {
+            Some(nat) => nat.on_idle(),
+            None => return Ok(Some(Stop::Idle)),
+        };
+        match wake {
+            Some((x, y)) => {
+                self.send(Packet {
+                    from: NAT_ADDRESS,
+                    to: 0,
+                    x,
+                    y,
+                });
+                Ok(None)
+            }
+            None => Ok(Some(Stop::NatStopped)),
+        }
+    }
+
+    // Runs until the network goes idle and the NAT (if any) won't wake it,
+    // or every machine halts.
+    pub fn run(&mut self) -> Result<Stop, MachineError> {
+        loop {
+            if let Some(stop) = self.advance()? {
+                return Ok(stop);
+            }
+        }
+    }
+
+    // Runs until a packet matching done is sent, and returns it. Returns
+    // None if the network stops first.
+    pub fn run_until<F>(&mut self, mut done: F) -> Result<Option<Packet>, MachineError>
+    where
+        F: FnMut(&Packet) -> bool,
+    {
+        let mut checked = 0;
+        loop {
+            let stop = self.advance()?;
+            if let Some(packet) = self.log[checked..].iter().find(|packet| done(packet)) {
+                return Ok(Some(*packet));
+            }
+            checked = self.log.len();
+            if stop.is_some() {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::asm::assemble;
+
+    // Machine 0 starts things off by sending (10, 20) to machine 1. Every
+    // machine passes the packets it gets on to the next address up, or to
+    // the NAT from the last address.
+    const RELAY: &str = "
+                IN [me]
+                JNZ [me], #wait
+                OUT #1
+                OUT #10
+                OUT #20
+        wait:   IN [x]
+                EQ [x], #-1, [t]
+                JNZ [t], #wait
+                IN [y]
+                ADD [me], #1, [to]
+                EQ [to], #3, [t]
+                JZ [t], #send
+                ADD #255, #0, [to]
+        send:   OUT [to]
+                OUT [x]
+                OUT [y]
+                JNZ #1, #wait
+        me:     .data 0
+        x:      .data 0
+        y:      .data 0
+        to:     .data 0
+        t:      .data 0
+    ";
+
+    fn relay() -> Program {
+        Program::new_from_vecs(&assemble(RELAY).unwrap(), &[])
+    }
+
+    #[test]
+    fn packets_and_idle() {
+        let mut network = Network::new(&relay(), 3);
+        let packet = network
+            .run_until(|packet| packet.to == NAT_ADDRESS)
+            .unwrap();
+        assert_eq!(
+            packet,
+            Some(Packet {
+                from: 2,
+                to: NAT_ADDRESS,
+                x: 10,
+                y: 20
+            })
+        );
+        assert_eq!(network.run().unwrap(), Stop::Idle);
+        let route: Vec<(usize, usize)> = network.log().iter().map(|p| (p.from, p.to)).collect();
+        assert_eq!(route, vec![(0, 1), (1, 2), (2, NAT_ADDRESS)]);
+        assert_eq!(network.packets_to(2).count(), 1);
+    }
+
+    #[test]
+    fn nat_wakes_machine_0() {
+        let mut network = Network::new(&relay(), 3);
+        network.set_nat(Box::new(ResendLast::default()));
+        assert_eq!(network.run().unwrap(), Stop::NatStopped);
+        let wakes: Vec<&Packet> = network
+            .log()
+            .iter()
+            .filter(|p| p.from == NAT_ADDRESS)
+            .collect();
+        assert_eq!(wakes.len(), 1);
+        assert_eq!((wakes[0].to, wakes[0].x, wakes[0].y), (0, 10, 20));
+        assert_eq!(network.packets_to(NAT_ADDRESS).count(), 2);
+    }
+
+    #[test]
+    fn custom_policy() {
+        // wakes machine 0 three times with decreasing y values
+        struct Countdown(i128);
+        impl NatPolicy for Countdown {
+            fn receive(&mut self, _packet: &Packet) {}
+            fn on_idle(&mut self) -> Option<(i128, i128)> {
+                self.0 -= 1;
+                if self.0 < 0 {
+                    None
+                } else {
+                    Some((self.0, 100 + self.0))
+                }
+            }
+        }
+
+        let mut network = Network::new(&relay(), 3);
+        network.set_nat(Box::new(Countdown(3)));
+        assert_eq!(network.run().unwrap(), Stop::NatStopped);
+        let ys: Vec<i128> = network.packets_to(NAT_ADDRESS).map(|p| p.y).collect();
+        assert_eq!(ys, vec![20, 102, 101, 100]);
+    }
+
+    #[test]
+    fn negative_destination() {
+        let code = assemble(
+            "
+                    OUT #-2
+                    OUT #3
+                    OUT #4
+            wait:   IN [t]
+                    JNZ #1, #wait
+            t:      .data 0
+            ",
+        )
+        .unwrap();
+        let mut network = Network::new(&Program::new_from_vecs(&code, &[]), 1);
+        assert_eq!(network.run().unwrap(), Stop::Idle);
+        assert!(network.log().is_empty());
+        assert_eq!(
+            network.misaddressed(),
+            &[Misaddressed {
+                from: 0,
+                to: -2,
+                x: 3,
+                y: 4
+            }]
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use super::{Executed, Instruction, Observer, Opcode, Program};
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// Counts what a run spends its instructions on:
+//
+//     let mut profiler = Profiler::default();
+//     prog.run_observed(&mut profiler)?;
+//     print!("{}", profiler.report(10));
+//
+// Counts accumulate across runs until reset.
+
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    pub instructions: u64,
+    pub inputs: u64,
+    pub outputs: u64,
+    opcodes: HashMap<Opcode, u64>,
+    // count and the last instruction seen there, which can change if the
+    // program modifies itself
+    addresses: HashMap<usize, (u64, Instruction)>,
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * count as f64 / total as f64
+    }
+}
+
+impl Profiler {
+    pub fn reset(&mut self) {
+        *self = Profiler::default();
+    }
+
+    pub fn opcode_count(&self, opcode: Opcode) -> u64 {
+        self.opcodes.get(&opcode).copied().unwrap_or(0)
+    }
+
+    pub fn address_count(&self, address: usize) -> u64 {
+        self.addresses.get(&address).map_or(0, |(count, _)| *count)
+    }
+
+    // by count, most executed first; ties go to the lower opcode / address
+    pub fn opcodes(&self) -> Vec<(Opcode, u64)> {
+        let mut opcodes: Vec<(Opcode, u64)> = self.opcodes.iter().map(|(o, c)| (*o, *c)).collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        opcodes
+    }
+
+    pub fn hotspots(&self) -> Vec<(usize, u64, Instruction)> {
+        let mut hotspots: Vec<(usize, u64, Instruction)> = self
+            .addresses
+            .iter()
+            .map(|(address, (count, inst))| (*address, *count, *inst))
+            .collect();
+        hotspots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hotspots
+    }
+
+    // opcode table plus the top addresses
+    pub fn report(&self, top: usize) -> String {
+        let mut text = String::new();
+        let total = self.instructions;
+        writeln!(
+            text,
+            "{} instructions, {} inputs, {} outputs",
+            total, self.inputs, self.outputs
+        )
+        .unwrap();
+        writeln!(text, "\nopcode        count       %").unwrap();
+        for (opcode, count) in self.opcodes() {
+            writeln!(
+                text,
+                "{:<6} {:>12} {:>6.1}%",
+                opcode.mnemonic(),
+                count,
+                percent(count, total)
+            )
+            .unwrap();
+        }
+        writeln!(text, "\naddress       count       %  instruction").unwrap();
+        for (address, count, inst) in self.hotspots().into_iter().take(top) {
+            writeln!(
+                text,
+                "{:>7} {:>11} {:>6.1}%  {}",
+                address,
+                count,
+                percent(count, total),
+                inst
+            )
+            .unwrap();
+        }
+        text
+    }
+}
+
+impl Observer for Profiler {
+    fn observe(&mut self, _prog: &Program, executed: &Executed) {
+        self.instructions += 1;
+        if executed.input.is_some() {
+            self.inputs += 1;
+        }
+        if executed.output.is_some() {
+            self.outputs += 1;
+        }
+        *self.opcodes.entry(executed.instruction.opcode).or_insert(0) += 1;
+        let entry = self
+            .addresses
+            .entry(executed.address)
+            .or_insert((0, executed.instruction));
+        entry.0 += 1;
+        entry.1 = executed.instruction;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::testing::run_observed_to_end;
+    use super::super::StopReason;
+
+    fn profile(code: &str, input: &str) -> Profiler {
+        let mut prog = Program::new(code, input).unwrap();
+        let mut profiler = Profiler::default();
+        assert_eq!(
+            run_observed_to_end(&mut prog, &mut profiler),
+            StopReason::Halted
+        );
+        profiler
+    }
+
+    #[test]
+    fn counts() {
+        // the quine: a 5 instruction loop run 16 times
+        let profiler = profile(
+            "109,1,204,-1,1001,100,1,100,1008,100,16,101,1006,101,0,99",
+            "",
+        );
+        assert_eq!(profiler.instructions, 16 * 5 + 1);
+        assert_eq!((profiler.inputs, profiler.outputs), (0, 16));
+        assert_eq!(profiler.opcode_count(Opcode::Out), 16);
+        assert_eq!(profiler.opcode_count(Opcode::Hlt), 1);
+        assert_eq!(profiler.opcode_count(Opcode::In), 0);
+        assert_eq!(profiler.address_count(12), 16);
+        assert_eq!(profiler.address_count(15), 1);
+        assert_eq!(profiler.address_count(3), 0);
+        assert_eq!(
+            profiler.opcodes(),
+            vec![
+                (Opcode::Add, 16),
+                (Opcode::Out, 16),
+                (Opcode::Jz, 16),
+                (Opcode::Eq, 16),
+                (Opcode::Arb, 16),
+                (Opcode::Hlt, 1),
+            ]
+        );
+        let hotspots: Vec<(usize, u64)> = profiler
+            .hotspots()
+            .iter()
+            .map(|(address, count, _)| (*address, *count))
+            .collect();
+        assert_eq!(
+            hotspots,
+            vec![(0, 16), (2, 16), (4, 16), (8, 16), (12, 16), (15, 1)]
+        );
+    }
+
+    #[test]
+    fn report() {
+        let mut profiler = profile("3, 9, 4, 9, 1006, 9, 8, 99, 99, 0", "0");
+        assert_eq!(
+            profiler.report(2),
+            "4 instructions, 1 inputs, 1 outputs\n\
+             \n\
+             opcode        count       %\n\
+             IN                1   25.0%\n\
+             OUT               1   25.0%\n\
+             JZ                1   25.0%\n\
+             HLT               1   25.0%\n\
+             \n\
+             address       count       %  instruction\n\
+             \x20     0           1   25.0%  IN [9]\n\
+             \x20     2           1   25.0%  OUT [9]\n"
+        );
+        profiler.reset();
+        assert_eq!(profiler.instructions, 0);
+        assert!(profiler.hotspots().is_empty());
+    }
+}
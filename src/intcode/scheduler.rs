@@ -0,0 +1,272 @@
+use super::{IntcodeError, Program, StopReason, Word};
+
+use std::fmt;
+
+// Runs any number of machines together on one thread, taking turns. Each
+// machine runs until it halts or needs input it hasn't got; its outputs go
+// straight into the inputs of whatever it is connected to:
+//
+//     let mut scheduler = Scheduler::new();
+//     let a = scheduler.add(Program::new(code, "5, 0")?);
+//     let b = scheduler.add(Program::new(code, "6")?);
+//     scheduler.connect(a, b);
+//     scheduler.connect(b, a);
+//     scheduler.run()?;
+//     scheduler.last_value(b, a)
+//
+// Outputs from a machine with no connections stay on its output queue.
+// Every method taking a MachineId panics if add didn't hand it out.
+
+pub type MachineId = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    // can run, or waiting for input that has since arrived
+    Ready,
+    // waiting for input nobody has sent yet
+    Blocked,
+    Halted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    AllHalted,
+    // some machines are still waiting for input and none can make progress
+    Deadlock,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineError {
+    pub machine: MachineId,
+    pub error: IntcodeError,
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "machine {}: {}", self.machine, self.error)
+    }
+}
+
+impl std::error::Error for MachineError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Connection {
+    pub from: MachineId,
+    pub to: MachineId,
+    // the most recent value sent along this connection
+    pub last: Option<i128>,
+    pub sent: usize,
+}
+
+// Scheduler::<i64>::default() for other word types
+#[derive(Debug, Clone, Default)]
+pub struct Scheduler<W: Word = i128> {
+    machines: Vec<Program<W>>,
+    connections: Vec<Connection>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::default()
+    }
+}
+
+impl<W: Word> Scheduler<W> {
+    pub fn add(&mut self, program: Program<W>) -> MachineId {
+        self.machines.push(program);
+        self.machines.len() - 1
+    }
+
+    // Sends from's outputs to to's input. A machine connected to several
+    // others sends every output to all of them.
+    pub fn connect(&mut self, from: MachineId, to: MachineId) {
+        for id in [from, to].iter() {
+            assert!(*id < self.machines.len(), "no machine {}", id);
+        }
+        self.connections.push(Connection {
+            from,
+            to,
+            last: None,
+            sent: 0,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.machines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.machines.is_empty()
+    }
+
+    pub fn program(&self, id: MachineId) -> &Program<W> {
+        &self.machines[id]
+    }
+
+    pub fn program_mut(&mut self, id: MachineId) -> &mut Program<W> {
+        &mut self.machines[id]
+    }
+
+    pub fn push_input(&mut self, id: MachineId, value: i128) {
+        self.machines[id].push_input(value);
+    }
+
+    pub fn status(&self, id: MachineId) -> Status {
+        let prog = &self.machines[id];
+        if prog.is_done() {
+            Status::Halted
+        } else if prog.needs_input() && prog.pending_input().is_empty() {
+            Status::Blocked
+        } else {
+            Status::Ready
+        }
+    }
+
+    pub fn connections(&self) -> &[Connection] {
+        &self.connections
+    }
+
+    // the last value sent from one machine to another, if they are
+    // connected and anything has been sent
+    pub fn last_value(&self, from: MachineId, to: MachineId) -> Option<i128> {
+        self.connections
+            .iter()
+            .find(|c| c.from == from && c.to == to)
+            .and_then(|c| c.last)
+    }
+
+    fn send(&mut self, from: MachineId, value: i128) {
+        let mut connected = false;
+        for connection in self.connections.iter_mut().filter(|c| c.from == from) {
+            connection.last = Some(value);
+            connection.sent += 1;
+            self.machines[connection.to].push_input(value);
+            connected = true;
+        }
+        if !connected {
+            self.machines[from].outputs.push_back(value);
+        }
+    }
+
+    // Runs one machine until it halts or blocks. Returns whether it could
+    // run at all.
+    pub fn run_machine(&mut self, id: MachineId) -> Result<bool, MachineError> {
+        if self.status(id) != Status::Ready {
+            return Ok(false);
+        }
+        loop {
+            let reason = self.machines[id]
+                .run_until_event()
+                .map_err(|error| MachineError { machine: id, error })?;
+            match reason {
+                StopReason::Output(value) => self.send(id, value),
+                _ => return Ok(true),
+            }
+        }
+    }
+
+    // Takes turns running every machine that can run, until they have all
+    // halted or the rest are all waiting on each other.
+    pub fn run(&mut self) -> Result<Outcome, MachineError> {
+        loop {
+            let mut progress = false;
+            for id in 0..self.machines.len() {
+                progress |= self.run_machine(id)?;
+            }
+            if (0..self.machines.len()).all(|id| self.status(id) == Status::Halted) {
+                return Ok(Outcome::AllHalted);
+            }
+            if !progress {
+                return Ok(Outcome::Deadlock);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::testing::DOUBLER;
+
+    fn amplifiers(code: &str, phases: &[i128]) -> i128 {
+        let mut scheduler = Scheduler::new();
+        for phase in phases {
+            scheduler.add(Program::new(code, &phase.to_string()).unwrap());
+        }
+        for id in 0..phases.len() {
+            scheduler.connect(id, (id + 1) % phases.len());
+        }
+        scheduler.push_input(0, 0);
+        assert_eq!(scheduler.run().unwrap(), Outcome::AllHalted);
+        scheduler.last_value(phases.len() - 1, 0).unwrap()
+    }
+
+    #[test]
+    fn feedback_loop() {
+        let code =
+            "3,26,1001,26,-4,26,3,27,1002,27,2,27,1,27,26,27,4,27,1001,28,-1,28,1005,28,6,99,0,0,5";
+        assert_eq!(amplifiers(code, &[9, 8, 7, 6, 5]), 139629729);
+    }
+
+    #[test]
+    fn deadlock_and_unconnected() {
+        let mut scheduler = Scheduler::new();
+        let a = scheduler.add(Program::new(DOUBLER, "1, 2").unwrap());
+        let b = scheduler.add(Program::new(DOUBLER, "").unwrap());
+        scheduler.connect(a, b);
+        assert_eq!(scheduler.run().unwrap(), Outcome::Deadlock);
+        assert_eq!(scheduler.status(a), Status::Blocked);
+        assert_eq!(scheduler.status(b), Status::Blocked);
+        assert_eq!(scheduler.program(b).queued_outputs(), vec![4, 8]);
+        assert_eq!(
+            scheduler.connections(),
+            &[Connection {
+                from: a,
+                to: b,
+                last: Some(4),
+                sent: 2
+            }]
+        );
+
+        scheduler.push_input(a, 0);
+        assert_eq!(scheduler.status(a), Status::Ready);
+        assert_eq!(scheduler.run().unwrap(), Outcome::Deadlock);
+        scheduler.push_input(b, 0);
+        assert_eq!(scheduler.run().unwrap(), Outcome::AllHalted);
+    }
+
+    #[test]
+    fn errors_name_the_machine() {
+        let mut scheduler = Scheduler::new();
+        scheduler.add(Program::new("104, 1, 99", "").unwrap());
+        scheduler.add(Program::new("3, 0, 42", "").unwrap());
+        scheduler.connect(0, 1);
+        let err = scheduler.run().unwrap_err();
+        assert_eq!(err.machine, 1);
+        assert_eq!(
+            err.to_string(),
+            "machine 1: invalid opcode 42 in instruction 42 at 2"
+        );
+    }
+
+    #[test]
+    fn other_word_types() {
+        let mut scheduler = Scheduler::<i64>::default();
+        let a = scheduler.add(Program::parse(DOUBLER, "3, 0").unwrap());
+        let b = scheduler.add(Program::parse(DOUBLER, "").unwrap());
+        scheduler.connect(a, b);
+        assert_eq!(scheduler.run().unwrap(), Outcome::Deadlock);
+        assert_eq!(scheduler.program(b).queued_outputs(), vec![12]);
+        scheduler.push_input(b, 0);
+        assert_eq!(scheduler.run().unwrap(), Outcome::AllHalted);
+    }
+
+    #[test]
+    #[should_panic(expected = "no machine 2")]
+    fn connect_unknown_machine() {
+        let mut scheduler = Scheduler::new();
+        let a = scheduler.add(Program::new(DOUBLER, "").unwrap());
+        scheduler.connect(a, 2);
+    }
+}
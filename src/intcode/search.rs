@@ -0,0 +1,344 @@
+use super::{IntcodeError, Program, Word};
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+
+// Explores an environment run by a program by cloning the machine at every
+// decision and trying each choice of input on a copy:
+//
+//     let search = Search::breadth_first(vec![vec![1], vec![2], vec![3], vec![4]]);
+//     match search.run(&prog, (0, 0), step, |pos| *pos == target)? {
+//         Outcome::Found(path) => path.choices.len(),
+//         ...
+//     }
+//
+// After each choice the copy runs until it wants input again, and a
+// function of the parent state, the choice and the outputs gives the state
+// it is now in (or None if it isn't worth going on from). A state that has
+// been seen before isn't explored again, so breadth first finds a shortest
+// path; depth and best first return the first one they come to.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    Breadth,
+    Depth,
+    // lowest score first
+    Best,
+}
+
+#[derive(Debug, Clone)]
+pub struct Path<K, W: Word = i128> {
+    // index into the choices of each step taken
+    pub choices: Vec<usize>,
+    // every input fed to the program, in order
+    pub inputs: Vec<i128>,
+    pub state: K,
+    // the program having taken the path
+    pub program: Program<W>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Outcome<K, W: Word = i128> {
+    Found(Box<Path<K, W>>),
+    // every reachable state has been seen and none is a goal
+    Exhausted,
+    // gave up after seeing max_states states, or with max_frontier of them
+    // waiting to be explored
+    Capped,
+}
+
+// parent pointers for rebuilding paths
+struct Node {
+    parent: Option<usize>,
+    choice: usize,
+}
+
+type Score<K> = Box<dyn Fn(&K) -> i64>;
+
+pub struct Search<K> {
+    strategy: Strategy,
+    choices: Vec<Vec<i128>>,
+    score: Option<Score<K>>,
+    max_states: usize,
+    max_frontier: usize,
+}
+
+const DEFAULT_MAX_STATES: usize = 100_000;
+// each state waiting to be explored holds a copy of the program, which for
+// a puzzle input is tens of kilobytes
+const DEFAULT_MAX_FRONTIER: usize = 1000;
+
+impl<K: Clone + Eq + Hash> Search<K> {
+    fn new(strategy: Strategy, choices: Vec<Vec<i128>>) -> Search<K> {
+        Search {
+            strategy,
+            choices,
+            score: None,
+            max_states: DEFAULT_MAX_STATES,
+            max_frontier: DEFAULT_MAX_FRONTIER,
+        }
+    }
+
+    pub fn breadth_first(choices: Vec<Vec<i128>>) -> Search<K> {
+        Search::new(Strategy::Breadth, choices)
+    }
+
+    pub fn depth_first(choices: Vec<Vec<i128>>) -> Search<K> {
+        Search::new(Strategy::Depth, choices)
+    }
+
+    pub fn best_first<F>(choices: Vec<Vec<i128>>, score: F) -> Search<K>
+    where
+        F: Fn(&K) -> i64 + 'static,
+    {
+        Search {
+            score: Some(Box::new(score)),
+            ..Search::new(Strategy::Best, choices)
+        }
+    }
+
+    // Stops with Capped once this many states have been seen.
+    pub fn max_states(self, max_states: usize) -> Search<K> {
+        Search { max_states, ..self }
+    }
+
+    // Stops with Capped once this many states are waiting to be explored,
+    // which bounds how many copies of the program are kept at once.
+    pub fn max_frontier(self, max_frontier: usize) -> Search<K> {
+        Search {
+            max_frontier,
+            ..self
+        }
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    fn path(&self, nodes: &[Node], mut id: usize) -> (Vec<usize>, Vec<i128>) {
+        let mut choices = vec![];
+        while let Some(parent) = nodes[id].parent {
+            choices.push(nodes[id].choice);
+            id = parent;
+        }
+        choices.reverse();
+        let inputs = choices
+            .iter()
+            .flat_map(|choice| self.choices[*choice].iter().copied())
+            .collect();
+        (choices, inputs)
+    }
+
+    // Searches from prog, which is in state start. Any input prog already
+    // has queued is used up before the first choice.
+    pub fn run<W, S, G>(
+        &self,
+        prog: &Program<W>,
+        start: K,
+        mut step: S,
+        mut goal: G,
+    ) -> Result<Outcome<K, W>, IntcodeError>
+    where
+        W: Word,
+        S: FnMut(&K, &[i128], &[i128]) -> Option<K>,
+        G: FnMut(&K) -> bool,
+    {
+        let mut prog = prog.clone();
+        prog.run_until_blocked()?;
+        prog.drain_outputs();
+        if goal(&start) {
+            return Ok(Outcome::Found(Box::new(Path {
+                choices: vec![],
+                inputs: vec![],
+                state: start,
+                program: prog,
+            })));
+        }
+
+        let mut nodes = vec![Node {
+            parent: None,
+            choice: 0,
+        }];
+        let mut seen = HashSet::new();
+        seen.insert(start.clone());
+        let mut pending = HashMap::new();
+        pending.insert(0, (prog, start));
+        let mut order = VecDeque::new();
+        let mut heap = BinaryHeap::new();
+        match &self.score {
+            Some(score) => heap.push(Reverse((score(&pending[&0].1), 0))),
+            None => order.push_back(0),
+        }
+
+        loop {
+            let id = match self.strategy {
+                Strategy::Breadth => order.pop_front(),
+                Strategy::Depth => order.pop_back(),
+                Strategy::Best => heap.pop().map(|Reverse((_, id))| id),
+            };
+            let id = match id {
+                Some(id) => id,
+                None => return Ok(Outcome::Exhausted),
+            };
+            let (prog, state) = pending.remove(&id).unwrap();
+            if prog.is_done() {
+                continue;
+            }
+            for (choice, inputs) in self.choices.iter().enumerate() {
+                let mut next = prog.clone();
+                for input in inputs.iter() {
+                    next.push_input(*input);
+                }
+                next.run_until_blocked()?;
+                let outputs = next.drain_outputs();
+                let next_state = match step(&state, inputs, &outputs) {
+                    Some(next_state) => next_state,
+                    None => continue,
+                };
+                if seen.contains(&next_state) {
+                    continue;
+                }
+                // a goal is still found when it would go over the cap
+                let found = goal(&next_state);
+                let full = seen.len() >= self.max_states || pending.len() >= self.max_frontier;
+                if !found && full {
+                    return Ok(Outcome::Capped);
+                }
+                seen.insert(next_state.clone());
+                nodes.push(Node {
+                    parent: Some(id),
+                    choice,
+                });
+                let next_id = nodes.len() - 1;
+                if found {
+                    let (choices, inputs) = self.path(&nodes, next_id);
+                    return Ok(Outcome::Found(Box::new(Path {
+                        choices,
+                        inputs,
+                        state: next_state,
+                        program: next,
+                    })));
+                }
+                match &self.score {
+                    Some(score) => heap.push(Reverse((score(&next_state), next_id))),
+                    None => order.push_back(next_id),
+                }
+                pending.insert(next_id, (next, next_state));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::asm::{assemble, to_code_string};
+
+    // starts at 1, and adds 1 to it or doubles it for each 1 or 2 read,
+    // outputting the new value each time
+    const COUNTER: &str = "
+        loop:   IN [c]
+                EQ [c], #1, [t]
+                JZ [t], #double
+                ADD [v], #1, [v]
+                JNZ #1, #out
+        double: MUL [v], #2, [v]
+        out:    OUT [v]
+                JNZ #1, #loop
+        v:      .data 1
+        c:      .data 0
+        t:      .data 0
+    ";
+
+    fn counter() -> Program {
+        Program::new_from_vecs(&assemble(COUNTER).unwrap(), &[])
+    }
+
+    fn choices() -> Vec<Vec<i128>> {
+        vec![vec![1], vec![2]]
+    }
+
+    // the state is the value, and anything past 100 is a dead end
+    fn step(_: &i128, _: &[i128], outputs: &[i128]) -> Option<i128> {
+        Some(outputs[0]).filter(|value| *value <= 100)
+    }
+
+    fn found<W: Word>(outcome: Outcome<i128, W>) -> Path<i128, W> {
+        match outcome {
+            Outcome::Found(path) => *path,
+            outcome => panic!("unexpected {:?}", outcome),
+        }
+    }
+
+    // runs the path's inputs on a fresh program
+    fn replay(path: &Path<i128>) -> i128 {
+        let mut prog = counter();
+        for input in path.inputs.iter() {
+            prog.push_input(*input);
+        }
+        prog.run_until_blocked().unwrap();
+        *prog.drain_outputs().last().unwrap()
+    }
+
+    #[test]
+    fn shortest_path() {
+        let search = Search::breadth_first(choices());
+        let path = found(search.run(&counter(), 1, step, |v| *v == 10).unwrap());
+        // 1 -> 2 -> 4 -> 5 -> 10
+        assert_eq!(path.choices.len(), 4);
+        assert_eq!(&path.inputs[1..], &[2, 1, 2]);
+        assert_eq!(path.state, 10);
+        assert_eq!(replay(&path), 10);
+        assert_eq!(path.program.peek(assemble(COUNTER).unwrap().len() - 3), 10);
+
+        let path = found(search.run(&counter(), 1, step, |v| *v == 1).unwrap());
+        assert!(path.inputs.is_empty());
+    }
+
+    #[test]
+    fn other_strategies() {
+        let search = Search::depth_first(choices());
+        let path = found(search.run(&counter(), 1, step, |v| *v == 10).unwrap());
+        assert_eq!(replay(&path), 10);
+        assert!(path.choices.len() >= 4);
+
+        let search = Search::best_first(choices(), |v: &i128| (37 - v).abs() as i64);
+        assert_eq!(search.strategy(), Strategy::Best);
+        let path = found(search.run(&counter(), 1, step, |v| *v == 37).unwrap());
+        assert_eq!(replay(&path), 37);
+    }
+
+    #[test]
+    fn other_word_types() {
+        let code = to_code_string(&assemble(COUNTER).unwrap());
+        let prog = Program::<i64>::parse(&code, "").unwrap();
+        let search = Search::breadth_first(choices());
+        let path = found(search.run(&prog, 1, step, |v| *v == 10).unwrap());
+        assert_eq!(path.choices.len(), 4);
+    }
+
+    #[test]
+    fn exhausted_and_capped() {
+        let search = Search::breadth_first(choices());
+        assert!(matches!(
+            search.run(&counter(), 1, step, |v| *v == 1000).unwrap(),
+            Outcome::Exhausted
+        ));
+        let search = Search::breadth_first(choices()).max_states(5);
+        assert!(matches!(
+            search.run(&counter(), 1, step, |v| *v == 1000).unwrap(),
+            Outcome::Capped
+        ));
+        let search = Search::breadth_first(choices()).max_frontier(2);
+        assert!(matches!(
+            search.run(&counter(), 1, step, |v| *v == 1000).unwrap(),
+            Outcome::Capped
+        ));
+        let search = Search::breadth_first(choices()).max_states(5);
+        // 1, 2, 3, 4 and 6 use up the cap before 5 turns up
+        let path = found(search.run(&counter(), 1, step, |v| *v == 5).unwrap());
+        assert_eq!(replay(&path), 5);
+    }
+}
@@ -0,0 +1,345 @@
+use super::{parse_values, Memory, Program};
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+// Saved machine state is a small text file:
+//
+//     intcode-state 1
+//     inst_ptr 12
+//     relative_base 0
+//     done 0
+//     input_needed 1
+//     input 5,7
+//     input_ptr 2
+//     outputs 14
+//     memory 3,100,1005,...
+//     sparse 20000=5,30000=-1
+//
+// The first line carries the format version. Lists may be empty, but
+// every field must be there, and nothing else may be. Sparse addresses are
+// past the end of memory.
+
+const MAGIC: &str = "intcode-state";
+const VERSION: u32 = 1;
+
+const FIELDS: [&str; 9] = [
+    "inst_ptr",
+    "relative_base",
+    "done",
+    "input_needed",
+    "input",
+    "input_ptr",
+    "outputs",
+    "memory",
+    "sparse",
+];
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    UnsupportedVersion(String),
+    Format { line: usize, message: String },
+    // a field that isn't in the file at all
+    Missing(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Io(err) => write!(f, "{}", err),
+            StateError::UnsupportedVersion(header) => {
+                write!(f, "unsupported state file header {:?}", header)
+            }
+            StateError::Format { line, message } => write!(f, "line {}: {}", line, message),
+            StateError::Missing(field) => write!(f, "missing {}", field),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<io::Error> for StateError {
+    fn from(err: io::Error) -> StateError {
+        StateError::Io(err)
+    }
+}
+
+fn join(values: impl Iterator<Item = String>) -> String {
+    values.collect::<Vec<String>>().join(",")
+}
+
+impl Program {
+    pub fn save_state<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut sparse: Vec<(&usize, &i128)> = self.memory.sparse.iter().collect();
+        sparse.sort();
+        writeln!(writer, "{} {}", MAGIC, VERSION)?;
+        writeln!(writer, "inst_ptr {}", self.inst_ptr)?;
+        writeln!(writer, "relative_base {}", self.relative_base)?;
+        writeln!(writer, "done {}", self.done as u8)?;
+        writeln!(writer, "input_needed {}", self.input_needed as u8)?;
+        writeln!(
+            writer,
+            "input {}",
+            join(self.input.iter().map(|v| v.to_string()))
+        )?;
+        writeln!(writer, "input_ptr {}", self.input_ptr)?;
+        writeln!(
+            writer,
+            "outputs {}",
+            join(self.outputs.iter().map(|v| v.to_string()))
+        )?;
+        writeln!(
+            writer,
+            "memory {}",
+            join(self.memory.dense.iter().map(|v| v.to_string()))
+        )?;
+        writeln!(
+            writer,
+            "sparse {}",
+            join(sparse.iter().map(|(a, v)| format!("{}={}", a, v)))
+        )?;
+        writer.flush()
+    }
+
+    pub fn load_state<R: BufRead>(reader: R) -> Result<Program, StateError> {
+        let mut lines = reader.lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        if header.trim() != format!("{} {}", MAGIC, VERSION) {
+            return Err(StateError::UnsupportedVersion(header));
+        }
+
+        let mut fields: HashMap<String, (usize, String)> = HashMap::new();
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            let number = i + 2;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (key, value) = match line.find(' ') {
+                Some(space) => (&line[..space], line[space + 1..].trim()),
+                None => (line.trim(), ""),
+            };
+            if !FIELDS.contains(&key) {
+                return Err(StateError::Format {
+                    line: number,
+                    message: format!("unknown field {:?}", key),
+                });
+            }
+            if fields
+                .insert(key.to_string(), (number, value.to_string()))
+                .is_some()
+            {
+                return Err(StateError::Format {
+                    line: number,
+                    message: format!("{} given twice", key),
+                });
+            }
+        }
+
+        let field = |key: &str| -> Result<(usize, &str), StateError> {
+            match fields.get(key) {
+                Some((line, value)) => Ok((*line, value.as_str())),
+                None => Err(StateError::Missing(key.to_string())),
+            }
+        };
+        let number = |key: &str| -> Result<usize, StateError> {
+            let (line, value) = field(key)?;
+            value.parse().map_err(|_| StateError::Format {
+                line,
+                message: format!("bad {} {:?}", key, value),
+            })
+        };
+        let flag = |key: &str| -> Result<bool, StateError> {
+            match number(key)? {
+                0 => Ok(false),
+                1 => Ok(true),
+                _ => Err(StateError::Format {
+                    line: field(key)?.0,
+                    message: format!("{} must be 0 or 1", key),
+                }),
+            }
+        };
+        let list = |key: &str| -> Result<Vec<i128>, StateError> {
+            let (line, value) = field(key)?;
+            parse_values(value).map_err(|err| StateError::Format {
+                line,
+                message: format!("bad {}: {}", key, err),
+            })
+        };
+
+        let memory = list("memory")?;
+        let mut sparse = HashMap::new();
+        let (sparse_line, sparse_value) = field("sparse")?;
+        for entry in sparse_value
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+        {
+            let parsed = match entry.find('=') {
+                Some(eq) => entry[..eq]
+                    .parse::<usize>()
+                    .ok()
+                    .zip(entry[eq + 1..].parse::<i128>().ok()),
+                None => None,
+            };
+            match parsed {
+                Some((address, _)) if address < memory.len() => {
+                    return Err(StateError::Format {
+                        line: sparse_line,
+                        message: format!("sparse address {} is inside memory", address),
+                    })
+                }
+                Some((address, value)) => sparse.insert(address, value),
+                None => {
+                    return Err(StateError::Format {
+                        line: sparse_line,
+                        message: format!("bad sparse entry {:?}", entry),
+                    })
+                }
+            };
+        }
+
+        let input = list("input")?;
+        let input_ptr = number("input_ptr")?;
+        if input_ptr > input.len() {
+            return Err(StateError::Format {
+                line: field("input_ptr")?.0,
+                message: format!("input_ptr {} is past the end of input", input_ptr),
+            });
+        }
+        Ok(Program {
+            memory: Memory {
+                dense: memory,
+                sparse,
+            },
+            inst_ptr: number("inst_ptr")?,
+            done: flag("done")?,
+            input_needed: flag("input_needed")?,
+            input,
+            input_ptr,
+            outputs: list("outputs")?.into_iter().collect::<VecDeque<i128>>(),
+            relative_base: number("relative_base")?,
+        })
+    }
+
+    pub fn save_state_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.save_state(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn load_state_from_file<P: AsRef<Path>>(path: P) -> Result<Program, StateError> {
+        Program::load_state(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::testing::DOUBLER;
+    use super::super::StopReason;
+
+    fn saved(prog: &Program) -> String {
+        let mut buffer = Vec::new();
+        prog.save_state(&mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn save_format() {
+        let mut prog = Program::new("109, 3, 3, 20, 104, 1, 99", "7").unwrap();
+        prog.poke(50000, -2).unwrap();
+        prog.step().unwrap();
+        assert_eq!(
+            saved(&prog),
+            "intcode-state 1\n\
+             inst_ptr 2\n\
+             relative_base 3\n\
+             done 0\n\
+             input_needed 0\n\
+             input 7\n\
+             input_ptr 0\n\
+             outputs \n\
+             memory 109,3,3,20,104,1,99\n\
+             sparse 50000=-2\n"
+        );
+    }
+
+    #[test]
+    fn round_trip_resumes() {
+        let mut prog = Program::new(DOUBLER, "4, 5").unwrap();
+        prog.poke(70000, 9).unwrap();
+        assert_eq!(prog.run_until_blocked().unwrap(), StopReason::NeedsInput);
+        let mut restored = Program::load_state(saved(&prog).as_bytes()).unwrap();
+        assert_eq!(saved(&restored), saved(&prog));
+        assert!(restored.needs_input());
+        assert_eq!(restored.peek(70000), 9);
+
+        prog.push_input(3);
+        restored.push_input(3);
+        assert_eq!(prog.run_until_outputs(3).unwrap(), vec![8, 10, 6]);
+        assert_eq!(restored.run_until_outputs(3).unwrap(), vec![8, 10, 6]);
+        assert_eq!(saved(&restored), saved(&prog));
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let path = std::env::temp_dir().join(format!("intcode-state-{}.txt", std::process::id()));
+        let mut prog = Program::new("104, 1, 104, 2, 99", "").unwrap();
+        prog.run_until_outputs(1).unwrap();
+        prog.save_state_to_file(&path).unwrap();
+        let mut restored = Program::load_state_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.run_until_blocked().unwrap(), StopReason::Halted);
+        assert_eq!(restored.drain_outputs(), vec![2]);
+    }
+
+    #[test]
+    fn bad_files() {
+        let prog = Program::new("99", "").unwrap();
+        let good = saved(&prog);
+        let load = |text: &str| {
+            Program::load_state(text.as_bytes())
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            load(&good.replace("state 1", "state 2")),
+            "unsupported state file header \"intcode-state 2\""
+        );
+        assert_eq!(
+            load(&good.replace("inst_ptr 0", "inst_ptr x")),
+            "line 2: bad inst_ptr \"x\""
+        );
+        assert_eq!(
+            load(&good.replace("done 0", "done 2")),
+            "line 4: done must be 0 or 1"
+        );
+        assert_eq!(
+            load(&good.replace("input_ptr 0", "input_ptr 3")),
+            "line 7: input_ptr 3 is past the end of input"
+        );
+        assert_eq!(
+            load(&good.replace("sparse ", "sparse 5=")),
+            "line 10: bad sparse entry \"5=\""
+        );
+        assert_eq!(
+            load(&good.replace("sparse ", "sparse 5=1,0=7")),
+            "line 10: sparse address 0 is inside memory"
+        );
+        assert_eq!(load(&good.replace("outputs \n", "")), "missing outputs");
+        assert_eq!(
+            load(&good.replace("outputs", "output")),
+            "line 8: unknown field \"output\""
+        );
+        assert_eq!(
+            load(&format!("{}done 1\n", good)),
+            "line 11: done given twice"
+        );
+        assert!(load(&good.replace("memory 99", "memory 9x")).starts_with("line 9: bad memory"));
+    }
+}
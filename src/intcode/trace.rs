@@ -0,0 +1,283 @@
+use super::{Executed, Observer, Program};
+
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+
+// Opt-in execution trace, one line per executed instruction. Hook it up
+// with Program::run_observed:
+//
+//     let mut tracer = Tracer::create("run.trace", Format::Text)?;
+//     prog.run_observed(&mut tracer)?;
+//     tracer.finish()?;
+//
+// Text lines are meant for reading, JSON lines for tools. Both are
+// deterministic so traces of two runs can be compared line by line with
+// diff or first_difference.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+pub fn format_text(n: u64, executed: &Executed) -> String {
+    let inst = &executed.instruction;
+    let args: Vec<String> = executed.args().iter().map(|a| format!("{}", a)).collect();
+    let mut line = format!(
+        "{:>8} {:>6}: {:<28}",
+        n,
+        executed.address,
+        format!("{}", inst)
+    );
+    if !args.is_empty() {
+        line.push_str(&format!(" | args {}", args.join(" ")));
+    }
+    if let Some(write) = executed.write {
+        line.push_str(&format!(
+            " | [{}] {} -> {}",
+            write.address, write.old, write.new
+        ));
+    }
+    if let Some((old, new)) = executed.relative_base {
+        line.push_str(&format!(" | rb {} -> {}", old, new));
+    }
+    if let Some(input) = executed.input {
+        line.push_str(&format!(" | in {}", input));
+    }
+    if let Some(output) = executed.output {
+        line.push_str(&format!(" | out {}", output));
+    }
+    if inst.opcode.is_jump() && executed.next != executed.address + inst.width() {
+        line.push_str(&format!(" | jump {}", executed.next));
+    }
+    line.trim_end().to_string()
+}
+
+fn json_list<T: ToString>(values: impl Iterator<Item = T>) -> String {
+    let values: Vec<String> = values.map(|v| v.to_string()).collect();
+    format!("[{}]", values.join(","))
+}
+
+fn json_option<T: ToString>(value: Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "null".to_string(),
+    }
+}
+
+pub fn format_json(n: u64, executed: &Executed) -> String {
+    let inst = &executed.instruction;
+    let write = match executed.write {
+        Some(write) => format!(
+            "{{\"address\":{},\"old\":{},\"new\":{}}}",
+            write.address, write.old, write.new
+        ),
+        None => "null".to_string(),
+    };
+    let relative_base = match executed.relative_base {
+        Some((old, new)) => format!("{{\"old\":{},\"new\":{}}}", old, new),
+        None => "null".to_string(),
+    };
+    format!(
+        "{{\"n\":{},\"address\":{},\"opcode\":\"{}\",\"raw\":{},\"args\":{},\"reads\":{},\
+         \"write\":{},\"relative_base\":{},\"input\":{},\"output\":{},\"next\":{}}}",
+        n,
+        executed.address,
+        inst.opcode.mnemonic(),
+        json_list(inst.encode().iter()),
+        json_list(executed.args().iter()),
+        json_list(executed.reads()),
+        write,
+        relative_base,
+        json_option(executed.input),
+        json_option(executed.output),
+        executed.next
+    )
+}
+
+pub struct Tracer<W: Write> {
+    writer: W,
+    format: Format,
+    count: u64,
+    // observe can't fail, so the first write error is kept for finish
+    error: Option<io::Error>,
+}
+
+impl Tracer<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, format: Format) -> io::Result<Self> {
+        Ok(Tracer::new(BufWriter::new(File::create(path)?), format))
+    }
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(writer: W, format: Format) -> Tracer<W> {
+        Tracer {
+            writer,
+            format,
+            count: 0,
+            error: None,
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Observer for Tracer<W> {
+    fn observe(&mut self, _prog: &Program, executed: &Executed) {
+        if self.error.is_some() {
+            return;
+        }
+        let line = match self.format {
+            Format::Text => format_text(self.count, executed),
+            Format::Json => format_json(self.count, executed),
+        };
+        self.count += 1;
+        if let Err(err) = writeln!(self.writer, "{}", line) {
+            self.error = Some(err);
+        }
+    }
+}
+
+// Where two traces first differ. line is 1 based, and a trace that ends
+// early shows up as None on its side.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    pub line: usize,
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+pub fn first_difference<A: BufRead, B: BufRead>(a: A, b: B) -> io::Result<Option<Difference>> {
+    let mut a = a.lines();
+    let mut b = b.lines();
+    let mut line = 0;
+    loop {
+        line += 1;
+        let (left, right) = (a.next().transpose()?, b.next().transpose()?);
+        if left.is_none() && right.is_none() {
+            return Ok(None);
+        }
+        if left != right {
+            return Ok(Some(Difference { line, left, right }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::testing::run_observed_to_end;
+    use super::super::StopReason;
+
+    fn trace(code: &str, input: &str, format: Format) -> String {
+        let mut prog = Program::new(code, input).unwrap();
+        let mut tracer = Tracer::new(Vec::new(), format);
+        assert_eq!(
+            run_observed_to_end(&mut prog, &mut tracer),
+            StopReason::Halted
+        );
+        String::from_utf8(tracer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn text() {
+        let text = trace("1, 9, 10, 3, 2, 3, 11, 0, 99, 30, 40, 50", "", Format::Text);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "       0      0: ADD [9], [10], [3]           | args 30 40 3 | [3] 3 -> 70",
+                "       1      4: MUL [3], [11], [0]           | args 70 50 0 | [0] 1 -> 3500",
+                "       2      8: HLT",
+            ]
+        );
+    }
+
+    #[test]
+    fn text_io_jumps_and_rb() {
+        let text = trace(
+            "3, 12, 109, 5, 1005, 12, 9, 99, 0, 204, 7, 99, 0",
+            "4",
+            Format::Text,
+        );
+        let lines: Vec<&str> = text.lines().map(|l| l.trim_start()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "0      0: IN [12]                      | args 12 | [12] 0 -> 4 | in 4",
+                "1      2: ARB #5                       | args 5 | rb 0 -> 5",
+                "2      4: JNZ [12], #9                 | args 4 9 | jump 9",
+                "3      9: OUT rb+7                     | args 4 | out 4",
+                "4     11: HLT",
+            ]
+        );
+    }
+
+    #[test]
+    fn json() {
+        let text = trace(
+            "3, 12, 109, 5, 1005, 12, 9, 99, 0, 204, 7, 99, 0",
+            "4",
+            Format::Json,
+        );
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "{\"n\":0,\"address\":0,\"opcode\":\"IN\",\"raw\":[3,12],\"args\":[12],\"reads\":[],\
+             \"write\":{\"address\":12,\"old\":0,\"new\":4},\"relative_base\":null,\
+             \"input\":4,\"output\":null,\"next\":2}"
+        );
+        assert_eq!(
+            lines[1],
+            "{\"n\":1,\"address\":2,\"opcode\":\"ARB\",\"raw\":[109,5],\"args\":[5],\"reads\":[],\
+             \"write\":null,\"relative_base\":{\"old\":0,\"new\":5},\
+             \"input\":null,\"output\":null,\"next\":4}"
+        );
+        assert!(lines[3].contains("\"reads\":[12],"));
+        assert!(lines[3].contains("\"output\":4,"));
+    }
+
+    #[test]
+    fn diff_two_runs() {
+        let code = "3, 9, 1008, 9, 5, 10, 4, 10, 99, 0, 0";
+        let a = trace(code, "5", Format::Text);
+        let b = trace(code, "6", Format::Text);
+        assert_eq!(first_difference(a.as_bytes(), a.as_bytes()).unwrap(), None);
+        let diff = first_difference(a.as_bytes(), b.as_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(diff.line, 1);
+        assert!(diff.left.unwrap().ends_with("in 5"));
+        assert!(diff.right.unwrap().ends_with("in 6"));
+        let diff = first_difference(a.as_bytes(), &b.as_bytes()[..0])
+            .unwrap()
+            .unwrap();
+        assert_eq!((diff.line, diff.right), (1, None));
+    }
+
+    #[test]
+    fn to_file() {
+        let path = std::env::temp_dir().join(format!("intcode-trace-{}.json", std::process::id()));
+        let mut prog = Program::new("104, 7, 99", "").unwrap();
+        let mut tracer = Tracer::create(&path, Format::Json).unwrap();
+        prog.run_observed(&mut tracer).unwrap();
+        prog.run_observed(&mut tracer).unwrap();
+        assert_eq!(tracer.count(), 2);
+        tracer.finish().unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(text.lines().count(), 2);
+    }
+}
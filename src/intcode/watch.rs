@@ -0,0 +1,312 @@
+use super::{Executed, IntcodeError, Program, StopReason};
+
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Range;
+
+// Memory watchpoints and self-modification checks, run in place of
+// run_until_event:
+//
+//     let mut watcher = Watcher::new(CodeWrites::Flag);
+//     watcher.watch(100..104, Access::Write);
+//     match watcher.run(&mut prog)? {
+//         Pause::Watch(hits) => ...,
+//         Pause::Stopped(reason) => ...,
+//     }
+//
+// A watch pauses after the instruction that touched the cell has run, so
+// the machine can be inspected and resumed. If that instruction was an OUT
+// its value goes on the output queue.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Any,
+}
+
+impl Access {
+    fn covers(self, other: Access) -> bool {
+        self == Access::Any || self == other
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: Range<usize>,
+    pub access: Access,
+}
+
+// What to do about writes into cells that have already run as code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeWrites {
+    Allow,
+    // let the write happen and keep a record of it
+    Flag,
+    // fail with IntcodeError::WriteIntoCode before the write happens
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hit {
+    pub watch: usize,
+    pub inst_ptr: usize,
+    pub address: usize,
+    pub access: Access,
+    // the value read, or the value written
+    pub value: i128,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeWrite {
+    pub inst_ptr: usize,
+    pub address: usize,
+    pub old: i128,
+    pub new: i128,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pause {
+    Stopped(StopReason),
+    Watch(Vec<Hit>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Watcher {
+    watchpoints: BTreeMap<usize, Watchpoint>,
+    next_id: usize,
+    code_writes: CodeWrites,
+    executed: HashSet<usize>,
+    flagged: Vec<CodeWrite>,
+}
+
+impl Watcher {
+    pub fn new(code_writes: CodeWrites) -> Watcher {
+        Watcher {
+            watchpoints: BTreeMap::new(),
+            next_id: 0,
+            code_writes,
+            executed: HashSet::new(),
+            flagged: vec![],
+        }
+    }
+
+    // returns an id for unwatch
+    pub fn watch(&mut self, range: Range<usize>, access: Access) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watchpoints.insert(id, Watchpoint { range, access });
+        id
+    }
+
+    pub fn unwatch(&mut self, id: usize) -> bool {
+        self.watchpoints.remove(&id).is_some()
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.watchpoints.iter().map(|(id, watch)| (*id, watch))
+    }
+
+    pub fn code_writes(&self) -> &[CodeWrite] {
+        &self.flagged
+    }
+
+    pub fn has_executed(&self, address: usize) -> bool {
+        self.executed.contains(&address)
+    }
+
+    fn hits(&self, executed: &Executed) -> Vec<Hit> {
+        let mut accesses: Vec<(usize, Access, i128)> = executed
+            .reads
+            .iter()
+            .zip(executed.args.iter())
+            .filter_map(|(read, value)| read.map(|address| (address, Access::Read, *value)))
+            .collect();
+        if let Some(write) = executed.write {
+            accesses.push((write.address, Access::Write, write.new));
+        }
+        let mut hits = vec![];
+        for (address, access, value) in accesses {
+            for (id, watch) in self.watchpoints.iter() {
+                if watch.range.contains(&address) && watch.access.covers(access) {
+                    hits.push(Hit {
+                        watch: *id,
+                        inst_ptr: executed.address,
+                        address,
+                        access,
+                        value,
+                    });
+                }
+            }
+        }
+        hits
+    }
+
+    pub fn step(&mut self, prog: &mut Program) -> Result<Option<Pause>, IntcodeError> {
+        let executed = match prog.preview()? {
+            Some(executed) => executed,
+            None => return Ok(prog.step_previewed(None).0.map(Pause::Stopped)),
+        };
+        // an instruction writing over itself counts as a write into code
+        let cells = executed.address..executed.address + executed.instruction.width();
+        if let Some(write) = executed.write {
+            if self.executed.contains(&write.address) || cells.contains(&write.address) {
+                match self.code_writes {
+                    CodeWrites::Allow => {}
+                    CodeWrites::Flag => self.flagged.push(CodeWrite {
+                        inst_ptr: executed.address,
+                        address: write.address,
+                        old: write.old,
+                        new: write.new,
+                    }),
+                    CodeWrites::Reject => {
+                        return Err(IntcodeError::WriteIntoCode {
+                            inst_ptr: executed.address,
+                            instruction: prog.peek(executed.address),
+                            address: write.address,
+                        })
+                    }
+                }
+            }
+        }
+        let (reason, executed) = prog.step_previewed(Some(executed));
+        let executed = executed.unwrap();
+        self.executed.extend(cells);
+        let hits = self.hits(&executed);
+        if hits.is_empty() {
+            return Ok(reason.map(Pause::Stopped));
+        }
+        if let Some(StopReason::Output(value)) = reason {
+            prog.outputs.push_back(value);
+        }
+        Ok(Some(Pause::Watch(hits)))
+    }
+
+    pub fn run(&mut self, prog: &mut Program) -> Result<Pause, IntcodeError> {
+        loop {
+            if let Some(pause) = self.step(prog)? {
+                return Ok(pause);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // adds up inputs into [20] until it reads a 0, then outputs the total
+    const SUM: &str =
+        "3, 21, 1006, 21, 13, 1, 20, 21, 20, 1105, 1, 0, 99, 4, 20, 99, 0, 0, 0, 0, 0, 0";
+
+    #[test]
+    fn write_watch() {
+        let mut prog = Program::new(SUM, "3, 4, 0").unwrap();
+        let mut watcher = Watcher::new(CodeWrites::Allow);
+        let id = watcher.watch(20..21, Access::Write);
+        assert_eq!(
+            watcher.run(&mut prog).unwrap(),
+            Pause::Watch(vec![Hit {
+                watch: id,
+                inst_ptr: 5,
+                address: 20,
+                access: Access::Write,
+                value: 3
+            }])
+        );
+        assert_eq!(prog.inst_ptr(), 9);
+        match watcher.run(&mut prog).unwrap() {
+            Pause::Watch(hits) => assert_eq!(hits[0].value, 7),
+            pause => panic!("unexpected {:?}", pause),
+        }
+        assert!(watcher.unwatch(id));
+        assert!(!watcher.unwatch(id));
+        assert_eq!(
+            watcher.run(&mut prog).unwrap(),
+            Pause::Stopped(StopReason::Output(7))
+        );
+    }
+
+    #[test]
+    fn read_watch_keeps_output() {
+        let mut prog = Program::new(SUM, "5, 0").unwrap();
+        let mut watcher = Watcher::new(CodeWrites::Allow);
+        watcher.watch(20..22, Access::Read);
+        let mut reads = vec![];
+        loop {
+            match watcher.run(&mut prog).unwrap() {
+                Pause::Watch(hits) => reads.extend(
+                    hits.iter()
+                        .map(|hit| (hit.inst_ptr, hit.address, hit.value)),
+                ),
+                Pause::Stopped(reason) => {
+                    assert_eq!(reason, StopReason::Halted);
+                    break;
+                }
+            }
+        }
+        assert_eq!(
+            reads,
+            vec![(2, 21, 5), (5, 20, 0), (5, 21, 5), (2, 21, 0), (13, 20, 5)]
+        );
+        assert_eq!(prog.drain_outputs(), vec![5]);
+    }
+
+    #[test]
+    fn code_writes() {
+        // OUT #1, then overwrite it with a HLT and jump back to it
+        let mut prog = Program::new("104, 1, 1101, 0, 99, 0, 1105, 1, 0", "").unwrap();
+        let mut watcher = Watcher::new(CodeWrites::Flag);
+        assert_eq!(
+            watcher.run(&mut prog).unwrap(),
+            Pause::Stopped(StopReason::Output(1))
+        );
+        assert_eq!(
+            watcher.run(&mut prog).unwrap(),
+            Pause::Stopped(StopReason::Halted)
+        );
+        assert!(watcher.has_executed(1));
+        assert_eq!(
+            watcher.code_writes(),
+            &[CodeWrite {
+                inst_ptr: 2,
+                address: 0,
+                old: 104,
+                new: 99
+            }]
+        );
+
+        let mut prog = Program::new("104, 1, 1101, 0, 99, 0, 1105, 1, 0", "").unwrap();
+        let mut watcher = Watcher::new(CodeWrites::Reject);
+        watcher.run(&mut prog).unwrap();
+        assert_eq!(
+            watcher.run(&mut prog).unwrap_err(),
+            IntcodeError::WriteIntoCode {
+                inst_ptr: 2,
+                instruction: 1101,
+                address: 0
+            }
+        );
+        assert_eq!((prog.inst_ptr(), prog.peek(0)), (2, 104));
+    }
+
+    #[test]
+    fn instruction_writing_over_itself() {
+        let code = "1101, 0, 99, 0, 99";
+        let mut prog = Program::new(code, "").unwrap();
+        let mut watcher = Watcher::new(CodeWrites::Flag);
+        watcher.run(&mut prog).unwrap();
+        assert_eq!(watcher.code_writes().len(), 1);
+
+        let mut prog = Program::new(code, "").unwrap();
+        let mut watcher = Watcher::new(CodeWrites::Reject);
+        assert_eq!(
+            watcher.run(&mut prog).unwrap_err(),
+            IntcodeError::WriteIntoCode {
+                inst_ptr: 0,
+                instruction: 1101,
+                address: 0
+            }
+        );
+        assert_eq!(prog.peek(0), 1101);
+    }
+}
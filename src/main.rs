@@ -1,6 +1,13 @@
-// #[macro_use]
-// extern crate itertools;
+#[macro_use]
+extern crate itertools;
 
+// earlier days aren't run from main, but are still built and tested
+#[allow(dead_code)]
+mod day_07;
+#[allow(dead_code)]
+mod day_09;
+#[allow(dead_code)]
+mod day_11;
 mod day_13;
 
 fn main() {